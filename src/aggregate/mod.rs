@@ -0,0 +1,98 @@
+use crate::MyHashMap;
+use crate::structs::AggregateFunction;
+use crate::value::Value;
+
+// Running state of one aggregate function over the rows of one group
+pub struct Accumulator {
+    function: AggregateFunction,
+    // Hash keys of the values already seen by a DISTINCT aggregate
+    seen: Option<MyHashMap<String, ()>>,
+    count: i64,
+    // Integer sum, None once it overflowed or a REAL value was added
+    integer_sum: Option<i64>,
+    real_sum: f64,
+    // Current MIN or MAX
+    best: Value,
+}
+
+impl Accumulator {
+    pub fn new(function: AggregateFunction, distinct: bool) -> Accumulator {
+        Accumulator {
+            function,
+            seen: if distinct { Some(MyHashMap::new()) } else { None },
+            count: 0,
+            integer_sum: Some(0),
+            real_sum: 0.0,
+            best: Value::Null,
+        }
+    }
+
+    // Add the argument value of a row, None stands for a row of COUNT(*)
+    pub fn update(&mut self, value: Option<&Value>) -> Result<(), String> {
+        let value = match value {
+            Some(value) => value,
+            None => {
+                self.count += 1;
+                return Ok(());
+            }
+        };
+        // Aggregates skip NULLs
+        if value.is_null() {
+            return Ok(());
+        }
+        if let Some(seen) = &mut self.seen {
+            let key = value.hash_key();
+            if seen.get(&key).is_some() {
+                return Ok(());
+            }
+            seen.insert(key, ());
+        }
+
+        match self.function {
+            AggregateFunction::Count => {}
+            AggregateFunction::Sum | AggregateFunction::Avg =>
+                match value {
+                    Value::Integer(i) => {
+                        self.integer_sum = self.integer_sum.and_then(|sum| sum.checked_add(*i));
+                        self.real_sum += *i as f64;
+                    }
+                    Value::Real(r) => {
+                        self.integer_sum = None;
+                        self.real_sum += r;
+                    }
+                    other => {
+                        return Err(
+                            format!("{} requires a numeric argument, got '{}'", self.function, other)
+                        );
+                    }
+                }
+            AggregateFunction::Min | AggregateFunction::Max => {
+                let ordering = value.sort_cmp(&self.best);
+                let better = if self.function == AggregateFunction::Min {
+                    ordering.is_lt()
+                } else {
+                    ordering.is_gt() || self.best.is_null()
+                };
+                if better {
+                    self.best = value.clone();
+                }
+            }
+        }
+        self.count += 1;
+        Ok(())
+    }
+
+    pub fn finish(&self) -> Value {
+        match self.function {
+            AggregateFunction::Count => Value::Integer(self.count),
+            _ if self.count == 0 => Value::Null,
+            AggregateFunction::Sum =>
+                match self.integer_sum {
+                    Some(sum) => Value::Integer(sum),
+                    None => Value::Real(self.real_sum),
+                }
+            AggregateFunction::Avg => Value::Real(self.real_sum / (self.count as f64)),
+            AggregateFunction::Min | AggregateFunction::Max => self.best.clone(),
+        }
+    }
+}
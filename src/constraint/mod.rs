@@ -0,0 +1,713 @@
+use std::collections::HashMap;
+use std::fs::{ self, File };
+use std::io::BufReader;
+use std::os::unix::fs::MetadataExt;
+use std::sync::{ Mutex, OnceLock };
+use std::time::SystemTime;
+use crate::{ MyHashMap, MyVec, Schema };
+use crate::structs::{ Column, ForeignKey, OnDelete, RowId, RowVersion };
+use crate::transaction::{ is_aborted, is_committed, Snapshot };
+use crate::utils::{
+    block_header,
+    encode_row,
+    read_record,
+    row_values,
+    scan_table_versions,
+};
+use crate::value::Value;
+use crate::wal::{ self, FileWrite };
+
+// PRIMARY KEY and UNIQUE constraints declared in schema.json. Every
+// constraint has an index at `{database}/{table}/{constraint}`, for the
+// primary key that is the `{table}_pk` file. An index holds the key of
+// every row version of its table in the format of the blocks, with the
+// same transaction ids, so a key stays taken while a transaction may still
+// see a version that holds it. Keys with a NULL in them are not indexed,
+// NULLs are never equal.
+//
+// Only statements that change a table read and write its indexes, and they
+// hold the exclusive lock of the table.
+//
+// FOREIGN KEY constraints are checked against the primary key or unique
+// index of the referenced table, which the statement holds a shared lock
+// on. A referenced row counts as present once the transaction that added
+// it has committed, whatever the snapshot of the statement sees.
+
+pub struct UniqueIndex {
+    name: String,
+    primary: bool,
+    table: String,
+    database: String,
+    path: String,
+    // Key columns and their positions in the rows of the table
+    columns: Vec<Column>,
+    positions: MyVec<usize>,
+    // Versions with their key, in the order they are stored
+    entries: MyVec<(RowVersion, String)>,
+    // Positions of the entries of every key
+    keys: MyHashMap<String, MyVec<usize>>,
+    // Entries from this one on are not stored yet
+    stored: usize,
+    // A stored entry changed, the whole index is stored again
+    changed: bool,
+}
+
+// Indexes as this process last stored them, by path. A statement takes an
+// index out while it works on it and it comes back once its changes are
+// stored, so an index that failed halfway is read from its file again.
+// The table locks keep statements from sharing an index.
+struct CachedIndex {
+    stamp: FileStamp,
+    header: String,
+    entries: MyVec<(RowVersion, String)>,
+    keys: MyHashMap<String, MyVec<usize>>,
+}
+
+// Inode, length and modification time, a write of any process changes one
+type FileStamp = (u64, u64, Option<SystemTime>);
+
+fn file_stamp(path: &str) -> Option<FileStamp> {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.ino(), metadata.len(), metadata.modified().ok()))
+}
+
+fn cached_indexes() -> std::sync::MutexGuard<'static, HashMap<String, CachedIndex>> {
+    static CACHE: OnceLock<Mutex<HashMap<String, CachedIndex>>> = OnceLock::new();
+    match CACHE.get_or_init(|| Mutex::new(HashMap::new())).lock() {
+        Ok(cache) => cache,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+// Build the indexes that are missing, fails if the rows of a table break
+// one of its constraints
+pub fn build_indexes(schema: &Schema) -> Result<(), String> {
+    for table in schema.constraints.keys() {
+        if !schema.structure.contains_key(table) {
+            return Err(format!("Constraints are declared for unknown table {}", table));
+        }
+        table_indexes(schema, table)?;
+    }
+    Ok(())
+}
+
+// Indexes of the constraints of a table
+pub fn table_indexes(schema: &Schema, table: &str) -> Result<MyVec<UniqueIndex>, String> {
+    let mut indexes = MyVec::new();
+    for (name, columns, primary) in index_specs(schema, table).iter() {
+        indexes.push(UniqueIndex::open(schema, table, name.clone(), columns, *primary)?);
+    }
+    Ok(indexes)
+}
+
+// Name, columns and kind of every index of a table
+fn index_specs(schema: &Schema, table: &str) -> MyVec<(String, Vec<String>, bool)> {
+    let mut specs = MyVec::new();
+    if let Some(constraints) = schema.constraints.get(table) {
+        if let Some(columns) = &constraints.primary_key {
+            specs.push((format!("{}_pk", table), columns.clone(), true));
+        }
+        for columns in constraints.unique.iter() {
+            specs.push((format!("{}_{}_key", table, columns.join("_")), columns.clone(), false));
+        }
+    }
+    specs
+}
+
+impl UniqueIndex {
+    fn open(
+        schema: &Schema,
+        table: &str,
+        name: String,
+        names: &[String],
+        primary: bool
+    ) -> Result<UniqueIndex, String> {
+        let table_columns = match schema.structure.get(table) {
+            Some(columns) => columns,
+            None => {
+                return Err("No such table in DB".to_string());
+            }
+        };
+        if names.is_empty() {
+            return Err(format!("Constraint {} has no columns", name));
+        }
+        let mut columns = Vec::new();
+        let mut positions = MyVec::new();
+        for column_name in names.iter() {
+            let position = match table_columns.iter().position(|column| &column.name == column_name) {
+                Some(position) => position,
+                None => {
+                    return Err(format!("Column {} of constraint {} is not in table {}", column_name, name, table));
+                }
+            };
+            if primary && table_columns[position].nullable {
+                return Err(format!("Column {}.{} of primary key {} must not be nullable", table, column_name, name));
+            }
+            columns.push(table_columns[position].clone());
+            positions.push(position);
+        }
+
+        let mut index = UniqueIndex {
+            path: format!("{}/{}/{}", schema.name, table, name),
+            name,
+            primary,
+            table: table.to_string(),
+            database: schema.name.clone(),
+            columns,
+            positions,
+            entries: MyVec::new(),
+            keys: MyHashMap::new(),
+            stored: 0,
+            changed: false,
+        };
+        if !index.take_cached() && !index.load()? {
+            index.build(schema)?;
+        }
+        Ok(index)
+    }
+
+    // Take the index out of the cache if its file has not changed since
+    fn take_cached(&mut self) -> bool {
+        let stamp = match file_stamp(&self.path) {
+            Some(stamp) => stamp,
+            None => {
+                return false;
+            }
+        };
+        let cached = match cached_indexes().remove(&self.path) {
+            Some(cached) => cached,
+            None => {
+                return false;
+            }
+        };
+        if cached.stamp != stamp || cached.header != block_header(&self.columns) {
+            return false;
+        }
+        self.entries = cached.entries;
+        self.keys = cached.keys;
+        self.stored = self.entries.len();
+        true
+    }
+
+    // Read the stored entries, false if the index is missing or was made
+    // for other columns
+    fn load(&mut self) -> Result<bool, String> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(_) => {
+                return Ok(false);
+            }
+        };
+        let mut reader = BufReader::new(file);
+        if read_record(&mut reader)? != Some(block_header(&self.columns)) {
+            return Ok(false);
+        }
+        while let Some(record) = read_record(&mut reader)? {
+            // The key is kept encoded, equal keys are encoded the same way
+            let mut fields = record.splitn(3, ',');
+            let mut xid = || {
+                fields
+                    .next()
+                    .and_then(|field| field.parse::<u64>().ok())
+                    .ok_or_else(|| format!("Corrupted entry in index {}: '{}'", self.name, record))
+            };
+            let version = RowVersion { xmin: xid()?, xmax: xid()? };
+            let key = match fields.next() {
+                Some(key) => key.to_string(),
+                None => {
+                    return Err(format!("Corrupted entry in index {}: '{}'", self.name, record));
+                }
+            };
+            self.push_entry(version, key);
+        }
+        self.stored = self.entries.len();
+        Ok(true)
+    }
+
+    // Index every version of the table and store the index
+    fn build(&mut self, schema: &Schema) -> Result<(), String> {
+        let table = self.table.clone();
+        let table_columns = match schema.structure.get(&table) {
+            Some(columns) => columns,
+            None => {
+                return Err("No such table in DB".to_string());
+            }
+        };
+        scan_table_versions(&table, schema, |_, version, row| {
+            self.add(version, &row_values(&table, table_columns, row), 0)
+        })?;
+        self.changed = true;
+        wal::apply(&self.database, self.write().into_iter().collect())?;
+        self.mark_stored();
+        Ok(())
+    }
+
+    // Take the key of a row the transaction adds, fails if another row
+    // holds it
+    pub fn insert(&mut self, values: &MyVec<Value>, xid: u64) -> Result<(), String> {
+        self.add(RowVersion { xmin: xid, xmax: 0 }, values, xid)
+    }
+
+    // Give back the key of a row version the transaction deletes
+    pub fn delete(&mut self, version: RowVersion, values: &MyVec<Value>, xid: u64) {
+        let key = match self.key(values) {
+            Some(key) => key,
+            None => {
+                return;
+            }
+        };
+        let found = self.entries_of(&key).find(|(_, entry)| {
+            entry.xmin == version.xmin && (entry.xmax == 0 || is_aborted(&self.database, entry.xmax))
+        });
+        if let Some((position, _)) = found {
+            self.entries[position].0.xmax = xid;
+            if position < self.stored {
+                self.changed = true;
+            }
+        }
+    }
+
+    // Keep the entries of the versions `keep` keeps, see transaction::vacuum
+    pub fn vacuum<F>(&mut self, mut keep: F) where F: FnMut(RowVersion) -> Option<RowVersion> {
+        let entries = std::mem::replace(&mut self.entries, MyVec::new());
+        self.keys = MyHashMap::new();
+        for (version, key) in entries.iter() {
+            if let Some(version) = keep(*version) {
+                self.push_entry(version, key.clone());
+            }
+        }
+        self.changed = true;
+    }
+
+    // The write of `write` has been applied
+    pub fn mark_stored(&mut self) {
+        self.stored = self.entries.len();
+        self.changed = false;
+    }
+
+    // The write that stores the changes of the index, if there are any
+    pub fn write(&self) -> Option<FileWrite> {
+        let (first, mut content) = if self.changed {
+            (0, format!("{}\n", block_header(&self.columns)))
+        } else {
+            (self.stored, String::new())
+        };
+        for position in first..self.entries.len() {
+            let (version, key) = &self.entries[position];
+            content.push_str(&format!("{},{},{}\n", version.xmin, version.xmax, key));
+        }
+        if self.changed {
+            Some(FileWrite::replace(self.path.clone(), content.into_bytes()))
+        } else if !content.is_empty() {
+            Some(FileWrite::append(self.path.clone(), content.into_bytes()))
+        } else {
+            None
+        }
+    }
+
+    fn add(&mut self, version: RowVersion, values: &MyVec<Value>, xid: u64) -> Result<(), String> {
+        let key = match self.key(values) {
+            Some(key) => key,
+            None => {
+                return Ok(());
+            }
+        };
+        if taken(&self.database, &version, xid) {
+            let held = self.entries_of(&key).any(|(_, entry)| taken(&self.database, entry, xid));
+            if held {
+                return Err(self.violation(values));
+            }
+        }
+        self.push_entry(version, key);
+        Ok(())
+    }
+
+    fn push_entry(&mut self, version: RowVersion, key: String) {
+        let position = self.entries.len();
+        match self.keys.get_mut(&key) {
+            Some(positions) => positions.push(position),
+            None => {
+                let mut positions = MyVec::new();
+                positions.push(position);
+                self.keys.insert(key.clone(), positions);
+            }
+        }
+        self.entries.push((version, key));
+    }
+
+    // Positions and versions of the entries with a key
+    fn entries_of(&self, key: &str) -> impl Iterator<Item = (usize, &RowVersion)> + '_ {
+        self.keys
+            .get(&key.to_string())
+            .into_iter()
+            .flat_map(move |positions| positions.iter().map(move |position| (*position, &self.entries[*position].0)))
+    }
+
+    // Key columns of a row of the table encoded as in the blocks, None if
+    // one of them is NULL
+    fn key(&self, values: &MyVec<Value>) -> Option<String> {
+        encode_key(&self.positions, values)
+    }
+
+    // Whether a row with the key is there for `xid` to refer to: it was
+    // added by `xid` or committed, and nobody has deleted it
+    fn holds(&self, key: &str, xid: u64) -> bool {
+        self.entries_of(key).any(|(_, version)| {
+            (version.xmin == xid || is_committed(&self.database, version.xmin)) &&
+                (version.xmax == 0 || is_aborted(&self.database, version.xmax))
+        })
+    }
+
+    fn violation(&self, values: &MyVec<Value>) -> String {
+        let columns: MyVec<&str> = self.columns
+            .iter()
+            .map(|column| column.name.as_str())
+            .collect();
+        let kind = if self.primary { "primary key" } else { "unique constraint" };
+        format!(
+            "Duplicate key {} violates {} {}",
+            describe_key(&columns, &self.positions, values),
+            kind,
+            self.name
+        )
+    }
+}
+
+// An index whose changes are all stored goes back to the cache
+impl Drop for UniqueIndex {
+    fn drop(&mut self) {
+        if self.changed || self.stored < self.entries.len() {
+            return;
+        }
+        if let Some(stamp) = file_stamp(&self.path) {
+            let cached = CachedIndex {
+                stamp,
+                header: block_header(&self.columns),
+                entries: std::mem::replace(&mut self.entries, MyVec::new()),
+                keys: std::mem::replace(&mut self.keys, MyHashMap::new()),
+            };
+            cached_indexes().insert(self.path.clone(), cached);
+        }
+    }
+}
+
+// Whether a version keeps its key from `xid`, the transaction that asks:
+// it did not roll back, and it is not deleted for good or by `xid`
+fn taken(database: &str, version: &RowVersion, xid: u64) -> bool {
+    if is_aborted(database, version.xmin) {
+        return false;
+    }
+    version.xmax == 0 || !(version.xmax == xid || is_committed(database, version.xmax))
+}
+
+// `(a, b)=(1, 2)` for error messages
+fn describe_key(columns: &MyVec<&str>, positions: &MyVec<usize>, values: &MyVec<Value>) -> String {
+    let values: MyVec<String> = positions
+        .iter()
+        .map(|position| values[*position].to_string())
+        .collect();
+    let values: MyVec<&str> = values
+        .iter()
+        .map(|value| value.as_str())
+        .collect();
+    format!("({})=({})", columns.join(", "), values.join(", "))
+}
+
+// A foreign key of `table` that refers to `parent`. The referenced columns
+// are those of a primary key or unique constraint of the parent, so its
+// index finds the referenced row. Rows with a NULL in the key refer to
+// nothing.
+#[derive(Clone)]
+pub struct Reference {
+    pub name: String,
+    pub table: String,
+    pub parent: String,
+    pub on_delete: OnDelete,
+    columns: MyVec<String>,
+    positions: MyVec<usize>,
+    parent_columns: MyVec<String>,
+    parent_positions: MyVec<usize>,
+}
+
+// Check the foreign keys of every table
+pub fn check_references(schema: &Schema) -> Result<(), String> {
+    for table in schema.constraints.keys() {
+        references_from(schema, table)?;
+    }
+    Ok(())
+}
+
+// Foreign keys of a table
+pub fn references_from(schema: &Schema, table: &str) -> Result<MyVec<Reference>, String> {
+    let mut references = MyVec::new();
+    if let Some(constraints) = schema.constraints.get(table) {
+        for foreign_key in constraints.foreign_keys.iter() {
+            references.push(Reference::resolve(schema, table, foreign_key)?);
+        }
+    }
+    Ok(references)
+}
+
+// Foreign keys of every table that refer to a table, in table name order
+pub fn references_to(schema: &Schema, parent: &str) -> Result<MyVec<Reference>, String> {
+    let mut tables: Vec<&String> = schema.constraints.keys().collect();
+    tables.sort();
+    let mut references = MyVec::new();
+    for table in tables {
+        for foreign_key in schema.constraints[table].foreign_keys.iter() {
+            if foreign_key.references == parent {
+                references.push(Reference::resolve(schema, table, foreign_key)?);
+            }
+        }
+    }
+    Ok(references)
+}
+
+// Check the rows a statement adds to a table and the rows it removes from
+// it against the foreign keys that involve the table. DELETE handles the
+// rows it removes itself, see Reference::referencing.
+pub fn check_foreign_keys(
+    schema: &Schema,
+    table: &str,
+    removed: &MyVec<MyVec<Value>>,
+    added: &MyVec<MyVec<Value>>,
+    xid: u64
+) -> Result<(), String> {
+    for reference in references_from(schema, table)?.iter() {
+        reference.check_parents(schema, added, xid)?;
+    }
+    for reference in references_to(schema, table)?.iter() {
+        // Keys that stay in the table are still there to refer to
+        let kept = reference.parent_keys(added);
+        let mut gone = MyVec::new();
+        for row in removed.iter() {
+            if let Some(key) = reference.parent_key(row) {
+                if kept.get(&key).is_none() {
+                    gone.push(row.clone());
+                }
+            }
+        }
+        reference.check_unreferenced(schema, &gone, None, xid, "Update")?;
+    }
+    Ok(())
+}
+
+impl Reference {
+    fn resolve(schema: &Schema, table: &str, foreign_key: &ForeignKey) -> Result<Reference, String> {
+        let name = format!("{}_{}_fkey", table, foreign_key.columns.join("_"));
+        let columns = match schema.structure.get(table) {
+            Some(columns) => columns,
+            None => {
+                return Err(format!("Constraints are declared for unknown table {}", table));
+            }
+        };
+        let parent_columns = match schema.structure.get(&foreign_key.references) {
+            Some(columns) => columns,
+            None => {
+                return Err(format!("Foreign key {} refers to unknown table {}", name, foreign_key.references));
+            }
+        };
+        let parent_constraints = schema.constraints.get(&foreign_key.references);
+        let referenced = match (&foreign_key.referenced_columns, parent_constraints) {
+            (Some(referenced), _) => referenced.clone(),
+            (None, Some(constraints)) if constraints.primary_key.is_some() =>
+                constraints.primary_key.clone().unwrap_or_default(),
+            (None, _) => {
+                return Err(format!("Foreign key {} refers to table {} that has no primary key", name, foreign_key.references));
+            }
+        };
+        if foreign_key.columns.is_empty() || foreign_key.columns.len() != referenced.len() {
+            return Err(format!("Foreign key {} has {} columns but refers to {}", name, foreign_key.columns.len(), referenced.len()));
+        }
+        let is_key = parent_constraints.is_some_and(|constraints| {
+            constraints.primary_key.as_ref() == Some(&referenced) || constraints.unique.contains(&referenced)
+        });
+        if !is_key {
+            return Err(
+                format!("Foreign key {} must refer to the primary key or a unique column set of {}", name, foreign_key.references)
+            );
+        }
+
+        let mut reference = Reference {
+            name: name.clone(),
+            table: table.to_string(),
+            parent: foreign_key.references.clone(),
+            on_delete: foreign_key.on_delete,
+            columns: foreign_key.columns.iter().cloned().collect(),
+            positions: MyVec::new(),
+            parent_columns: referenced.iter().cloned().collect(),
+            parent_positions: MyVec::new(),
+        };
+        for (column_name, parent_name) in foreign_key.columns.iter().zip(referenced.iter()) {
+            let position = columns.iter().position(|column| &column.name == column_name);
+            let parent_position = parent_columns.iter().position(|column| &column.name == parent_name);
+            let (position, parent_position) = match (position, parent_position) {
+                (Some(position), Some(parent_position)) => (position, parent_position),
+                (None, _) => {
+                    return Err(format!("Column {} of foreign key {} is not in table {}", column_name, name, table));
+                }
+                (_, None) => {
+                    return Err(
+                        format!("Column {} of foreign key {} is not in table {}", parent_name, name, foreign_key.references)
+                    );
+                }
+            };
+            if columns[position].column_type != parent_columns[parent_position].column_type {
+                return Err(format!("Foreign key {}: {}.{} and {}.{} have different types", name, table, column_name, foreign_key.references, parent_name));
+            }
+            if foreign_key.on_delete == OnDelete::SetNull && !columns[position].nullable {
+                return Err(format!("Foreign key {} sets {}.{} to NULL but the column is not nullable", name, table, column_name));
+            }
+            reference.positions.push(position);
+            reference.parent_positions.push(parent_position);
+        }
+        Ok(reference)
+    }
+
+    // The key a row of the table refers to, None if it has a NULL
+    pub fn key(&self, values: &MyVec<Value>) -> Option<String> {
+        encode_key(&self.positions, values)
+    }
+
+    // The key of a row of the parent, as rows of the table refer to it
+    pub fn parent_key(&self, values: &MyVec<Value>) -> Option<String> {
+        encode_key(&self.parent_positions, values)
+    }
+
+    // Set of the keys of rows of the parent
+    fn parent_keys(&self, rows: &MyVec<MyVec<Value>>) -> MyHashMap<String, ()> {
+        let mut keys = MyHashMap::new();
+        for row in rows.iter() {
+            if let Some(key) = self.parent_key(row) {
+                keys.insert(key, ());
+            }
+        }
+        keys
+    }
+
+    // Check that the rows refer to rows of the parent that are there
+    fn check_parents(&self, schema: &Schema, rows: &MyVec<MyVec<Value>>, xid: u64) -> Result<(), String> {
+        let keys: MyVec<(String, &MyVec<Value>)> = rows
+            .iter()
+            .filter_map(|row| self.key(row).map(|key| (key, row)))
+            .collect();
+        if keys.len() == 0 {
+            return Ok(());
+        }
+        let specs = index_specs(schema, &self.parent);
+        let index = match specs.iter().find(|(_, columns, _)| columns.iter().eq(self.parent_columns.iter())) {
+            Some((name, columns, primary)) => UniqueIndex::open(schema, &self.parent, name.clone(), columns, *primary)?,
+            None => {
+                return Err(format!("Foreign key {} has no index on table {}", self.name, self.parent));
+            }
+        };
+        for (key, row) in keys.iter() {
+            if !index.holds(key, xid) {
+                let columns: MyVec<&str> = self.columns.iter().map(|column| column.as_str()).collect();
+                return Err(
+                    format!(
+                        "Insert or update on table {} violates foreign key {}: key {} is not present in table {}",
+                        self.table,
+                        self.name,
+                        describe_key(&columns, &self.positions, row),
+                        self.parent
+                    )
+                );
+            }
+        }
+        Ok(())
+    }
+
+    // Fail if a row of the table refers to one of the rows of the parent.
+    // `deleted` holds the hash keys of the RowIds of rows of the table the
+    // statement deletes along with the parents, they refer to nothing then.
+    pub fn check_unreferenced(
+        &self,
+        schema: &Schema,
+        parents: &MyVec<MyVec<Value>>,
+        deleted: Option<&MyHashMap<String, ()>>,
+        xid: u64,
+        action: &str
+    ) -> Result<(), String> {
+        let keys = self.parent_keys(parents);
+        if keys.iter().next().is_none() {
+            return Ok(());
+        }
+        let columns = &schema.structure[&self.table];
+        scan_table_versions(&self.table, schema, |id, version, row| {
+            if deleted.is_some_and(|deleted| deleted.get(&id.hash_key()).is_some()) {
+                return Ok(());
+            }
+            let values = row_values(&self.table, columns, row);
+            let refers = self.key(&values).is_some_and(|key| keys.get(&key).is_some());
+            if refers && taken(&schema.name, &version, xid) {
+                let parent_columns: MyVec<&str> = self.parent_columns.iter().map(|column| column.as_str()).collect();
+                let parent_values: MyVec<Value> = self.positions.iter().map(|position| values[*position].clone()).collect();
+                let positions: MyVec<usize> = (0..parent_columns.len()).collect();
+                return Err(
+                    format!(
+                        "{} on table {} violates foreign key {}: key {} is still referenced from table {}",
+                        action,
+                        self.parent,
+                        self.name,
+                        describe_key(&parent_columns, &positions, &parent_values),
+                        self.table
+                    )
+                );
+            }
+            Ok(())
+        })
+    }
+
+    // The rows of the table that refer to one of the rows of the parent and
+    // where they are stored, for a DELETE that goes on to them. A referring
+    // row the statement cannot see or that another transaction is changing
+    // is a conflict.
+    pub fn referencing(
+        &self,
+        schema: &Schema,
+        snapshot: &Snapshot,
+        parents: &MyVec<MyVec<Value>>,
+        xid: u64
+    ) -> Result<MyVec<(RowId, MyVec<Value>)>, String> {
+        let keys = self.parent_keys(parents);
+        let mut rows = MyVec::new();
+        if keys.iter().next().is_none() {
+            return Ok(rows);
+        }
+        let columns = &schema.structure[&self.table];
+        scan_table_versions(&self.table, schema, |id, version, row| {
+            let values = row_values(&self.table, columns, row);
+            let refers = self.key(&values).is_some_and(|key| keys.get(&key).is_some());
+            if !refers || !taken(&schema.name, &version, xid) {
+                return Ok(());
+            }
+            let changing = version.xmax != 0 && !is_aborted(&schema.name, version.xmax);
+            if !snapshot.sees_version(&version) || changing {
+                return Err(
+                    format!("Could not serialize: a row of {} was changed by a concurrent transaction", self.table)
+                );
+            }
+            rows.push((id, values));
+            Ok(())
+        })?;
+        Ok(rows)
+    }
+
+    // Positions of the key columns in the rows of the table
+    pub fn positions(&self) -> &MyVec<usize> {
+        &self.positions
+    }
+}
+
+fn encode_key(positions: &MyVec<usize>, values: &MyVec<Value>) -> Option<String> {
+    let mut key = MyVec::new();
+    for position in positions.iter() {
+        let value = values[*position].clone();
+        if value.is_null() {
+            return None;
+        }
+        key.push(value);
+    }
+    Some(encode_row(&key))
+}
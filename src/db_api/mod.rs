@@ -1,29 +1,62 @@
 use std::io::{ Read, Write };
-use std::fs::OpenOptions;
 use std::path::Path;
-use crate::querry_parser::{ parse_insert, parse_delete, parse_select };
-use crate::structs::DbResponse;
+use crate::querry_parser::parse_query;
+use crate::executor::execute_statement;
+use crate::structs::{ DbResponse, Statement };
+use crate::transaction::{ vacuum, Session };
+use crate::lock::{ lock_tables, statement_locks };
+use crate::constraint::{ build_indexes, check_references };
+use crate::utils::block_header;
 use crate::Schema;
+use crate::MyVec;
+use crate::wal::{ self, FileWrite };
 use std::fs;
+use std::sync::Mutex;
 
-pub fn execute_query(query: String, schema: &Schema) -> DbResponse {
-    if query.starts_with("INSERT INTO") {
-        parse_insert(query, &schema)
-    } else if query.starts_with("DELETE FROM") {
-        parse_delete(query, &schema)
-    } else if query.starts_with("SELECT") {
-        parse_select(query, &schema)
-    } else {
-        DbResponse::Error("Bad query".to_string())
+pub fn execute_query(query: String, schema: &Schema, session: &mut Session) -> DbResponse {
+    let statement = match parse_query(&query) {
+        Ok(statement) => statement,
+        Err(e) => {
+            return DbResponse::Error(e.to_string());
+        }
+    };
+    let result = match statement {
+        Statement::Begin => session.begin(schema),
+        Statement::Commit => session.commit(),
+        Statement::Rollback => session.rollback(),
+        Statement::Vacuum(table) => vacuum(schema, table.as_deref()),
+        statement => {
+            return execute_in_session(statement, schema, session);
+        }
+    };
+    match result {
+        Ok(()) => DbResponse::Success(None),
+        Err(e) => DbResponse::Error(e),
     }
 }
 
+fn execute_in_session(statement: Statement, schema: &Schema, session: &mut Session) -> DbResponse {
+    let writes = matches!(statement, Statement::Insert(_) | Statement::Update(_) | Statement::Delete(_));
+    let _locks = match lock_tables(schema, &statement_locks(&statement, schema)) {
+        Ok(locks) => locks,
+        Err(e) => {
+            return DbResponse::Error(e);
+        }
+    };
+    session.run(schema, writes, |snapshot| execute_statement(statement, schema, snapshot))
+}
+
 pub fn init_db(schema: &Schema) {
     // Clear all CSV files except 1.csv
     // clear_csv_files(&schema);
 
     // Create the database
     fs::create_dir_all(&schema.name).expect("failed to create dir for DB");
+    // Finish the change a crash interrupted. Transactions left open by the
+    // previous run never committed, so their row versions stay invisible.
+    if let Err(e) = wal::recover(&schema.name) {
+        panic!("failed to recover the database: {}", e);
+    }
     for (table_name, columns) in &schema.structure {
         // Create tables
         let table_path = format!("{}/{}", &schema.name, &table_name);
@@ -31,23 +64,17 @@ pub fn init_db(schema: &Schema) {
 
         // Create CSV files
         let data_path = format!("{}/{}", &table_path, "1.csv");
-        let block_path = format!("{}/{}_pk", &table_path, table_name);
         let block_path_sequence = format!("{}/{}_pk_sequence", &table_path, table_name);
         let lock_path = format!("{}/{}_lock", &table_path, table_name);
         let data_path = Path::new(data_path.as_str());
-        let block_path = Path::new(block_path.as_str());
         let block_path_sequence = Path::new(block_path_sequence.as_str());
         let lock_path = Path::new(lock_path.as_str());
         if !data_path.exists() {
             let mut file = fs::File::create(data_path).expect("failed to create csv file");
             // Update CSV files
-            let header = columns.join(",");
+            let header = block_header(columns);
             writeln!(file, "{}", header).expect("failed to make header of csv file");
         }
-        if !block_path.exists() {
-            let mut file = fs::File::create(block_path).expect("failed to create block file");
-            writeln!(file, "0").unwrap();
-        }
         if !block_path_sequence.exists() {
             let mut file = fs::File
                 ::create(block_path_sequence)
@@ -62,12 +89,19 @@ pub fn init_db(schema: &Schema) {
             writeln!(file, "0").expect("Failed to write to file");
         }
     }
+    // Indexes of the constraints, the primary key one is the `{table}_pk` file
+    if let Err(e) = build_indexes(schema) {
+        panic!("failed to build the indexes: {}", e);
+    }
+    if let Err(e) = check_references(schema) {
+        panic!("invalid foreign key: {}", e);
+    }
 }
 
 pub fn clear_csv_files(schema: &Schema) {
     let db_path = &schema.name;
 
-    for (table_name, _) in &schema.structure {
+    for table_name in schema.structure.keys() {
         let table_path = format!("{}/{}", db_path, table_name);
 
         // Attempt to get the list of files in the table directory
@@ -81,53 +115,47 @@ pub fn clear_csv_files(schema: &Schema) {
     }
 }
 
-// pub fn is_locked(table_name: &str, schema: &Schema) -> bool {
-//     let lock_path = format!("{}/{}/{}_lock", schema.name, table_name, table_name);
-//     if let Ok(mut file) = fs::File::open(&lock_path) {
-//         let mut content = String::new();
-//         file.read_to_string(&mut content).expect("Failed to read lock file");
-//         return content.trim() == "1";
-//     }
-//     false
-// }
-
-// pub fn lock_table(table_name: &str, schema: &Schema) {
-//     let lock_path = format!("{}/{}/{}_lock", schema.name, table_name, table_name);
-//     let mut file = fs::File::create(&lock_path).expect("failed to create lock file");
-//     writeln!(file, "1").expect("Failed to write to lock file");
-// }
-
-// pub fn unlock_table(table_name: &str, schema: &Schema) {
-//     let lock_path = format!("{}/{}/{}_lock", schema.name, table_name, table_name);
-//     let mut file = fs::File::create(&lock_path).expect("failed to create lock file");
-//     writeln!(file, "0").expect("Failed to write to lock file");
-// }
-
-pub fn increment_pk_sequence(schema_name: &str, table_name: &str) -> i32 {
+// Reserve `count` primary keys of a table and return the first of them.
+// The sequence file holds the last key given out. Keys of statements that
+// fail or roll back are not given out again.
+pub fn reserve_pk_sequence(schema_name: &str, table_name: &str, count: usize) -> Result<i64, String> {
+    // Reading and writing the sequence is one step for all connection tasks
+    static SEQUENCE_LOCK: Mutex<()> = Mutex::new(());
+    let _guard = match SEQUENCE_LOCK.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
     let sequence_path = format!("{}/{}/{}_pk_sequence", schema_name, table_name, table_name);
     let sequence = Path::new(&sequence_path);
 
-    if sequence.exists() {
-        let mut file = fs::File::open(&sequence).expect("Failed to open pk_sequence file");
+    let current_value: i64 = if sequence.exists() {
         let mut content = String::new();
-        file.read_to_string(&mut content).expect("Failed to read pk_sequence file");
-
-        let current_value: u64 = content
-            .trim()
-            .parse()
-            .expect("Invalid number in pk_sequence file");
-        let new_value = current_value + 1;
-
-        let mut file = OpenOptions::new()
-            .write(true)
-            .truncate(true)
-            .open(&sequence)
-            .expect("Failed to open pk_sequence file for writing");
-        writeln!(file, "{}", new_value).expect("Failed to write new pk_sequence value to file");
-        new_value as i32
+        match fs::File::open(sequence).and_then(|mut file| file.read_to_string(&mut content)) {
+            Ok(_) => {}
+            Err(e) => {
+                return Err(format!("Failed to read {}: {}", sequence_path, e));
+            }
+        }
+        match content.trim().parse() {
+            Ok(value) => value,
+            Err(_) => {
+                return Err(format!("Invalid number in {}", sequence_path));
+            }
+        }
     } else {
-        let mut file = fs::File::create(&sequence).expect("Failed to create pk_sequence file");
-        writeln!(file, "1").expect("Failed to write to new pk_sequence file");
-        1_i32
-    }
+        0
+    };
+    let last_value = match current_value.checked_add(count as i64) {
+        Some(value) => value,
+        None => {
+            return Err(format!("The primary key sequence of {} is exhausted", table_name));
+        }
+    };
+
+    // A replaced file is written aside and renamed over the old one, see
+    // wal::apply, so a crash leaves either value
+    let mut writes = MyVec::new();
+    writes.push(FileWrite::replace(sequence_path, format!("{}\n", last_value).into_bytes()));
+    wal::apply(schema_name, writes)?;
+    Ok(current_value + 1)
 }
@@ -0,0 +1,1682 @@
+use crate::{ Schema, DbResponse };
+use crate::structs::{
+    Column,
+    ColumnRef,
+    CompareOp,
+    Expr,
+    FromItem,
+    JoinKind,
+    OrderItem,
+    OnDelete,
+    Row,
+    RowId,
+    RowVersion,
+    SelectItem,
+    Statement,
+    SelectQuery,
+    TableRef,
+    InsertQuery,
+    InsertSource,
+    UpdateQuery,
+    DeleteQuery,
+};
+use crate::{ MyVec, MyHashMap };
+use crate::value::{ ColumnType, Value };
+use crate::aggregate::Accumulator;
+use crate::functions::{ apply_binary, call_function, negate };
+use crate::wal::{ self, FileWrite };
+use crate::db_api::reserve_pk_sequence;
+use crate::transaction::{ is_aborted, Snapshot };
+use crate::constraint::{ check_foreign_keys, references_to, table_indexes, Reference, UniqueIndex };
+use crate::utils::{
+    cartesian_product,
+    hash_join,
+    build_hash_table,
+    join_key,
+    group_key,
+    read_all_table_data,
+    find_not_full_csv,
+    like_match,
+    encode_version,
+    row_values,
+    block_header,
+    scan_table_versions,
+    table_block_writes,
+};
+use std::path::Path;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+pub fn execute_statement(statement: Statement, schema: &Schema, snapshot: &Snapshot) -> DbResponse {
+    match statement {
+        Statement::Select(query) => execute_select(query, schema, snapshot),
+        Statement::Insert(query) => execute_insert(query, schema, snapshot),
+        Statement::Update(query) => execute_update(query, schema, snapshot),
+        Statement::Delete(query) => execute_delete(query, schema, snapshot),
+        // Transaction control needs the session, see db_api::execute_query
+        Statement::Begin | Statement::Commit | Statement::Rollback | Statement::Vacuum(_) =>
+            DbResponse::Error("Transaction statements must go through a session".to_string()),
+    }
+}
+
+//Execute Functions
+fn execute_insert(query: InsertQuery, schema: &Schema, snapshot: &Snapshot) -> DbResponse {
+    match insert_rows(query, schema, snapshot) {
+        Ok(returned) => DbResponse::Success(returned),
+        Err(e) => DbResponse::Error(e),
+    }
+}
+
+// Write the rows of an INSERT, the result holds the RETURNING rows if the
+// query asked for them
+fn insert_rows(
+    mut query: InsertQuery,
+    schema: &Schema,
+    snapshot: &Snapshot
+) -> Result<Option<Vec<Vec<Value>>>, String> {
+    let table = query.table.clone();
+    let columns = match schema.structure.get(&table) {
+        Some(columns) => columns,
+        None => {
+            return Err("No such table in DB".to_string());
+        }
+    };
+
+    // Column index of every value in a VALUES row. The first column is the
+    // primary key, it is generated below.
+    let targets: MyVec<usize> = match &query.columns {
+        None => (1..columns.len()).collect(),
+        Some(names) => {
+            let mut targets = MyVec::new();
+            for name in names.iter() {
+                let index = match columns.iter().position(|column| &column.name == name) {
+                    Some(index) => index,
+                    None => {
+                        return Err(format!("Column {} is not in table {}", name, table));
+                    }
+                };
+                if index == 0 {
+                    return Err(format!("Column {}.{} is generated and cannot be inserted", table, name));
+                }
+                if targets.iter().any(|target| *target == index) {
+                    return Err(format!("Column {} specified more than once", name));
+                }
+                targets.push(index);
+            }
+            targets
+        }
+    };
+
+    let scope = Scope::single(&table, schema, snapshot)?;
+    resolve_select_items(&mut query.returning, &scope)?;
+    let mut returning = output_exprs(&query.returning, &scope);
+    for output in returning.iter_mut() {
+        if contains_aggregate(output) {
+            return Err("Aggregate functions are not allowed in RETURNING".to_string());
+        }
+        prepare_subqueries(output, schema, snapshot)?;
+    }
+
+    let mut defaults: MyVec<Value> = MyVec::new();
+    for column in columns.iter().skip(1) {
+        defaults.push(column.default_value().map_err(|e| format!("{}: {}", table, e))?);
+    }
+
+    // Rows of INSERT ... SELECT are read completely before anything is
+    // written, so a table can be copied into itself
+    let values_rows: MyVec<MyVec<Option<Value>>> = match query.source {
+        InsertSource::Values(rows) => rows,
+        InsertSource::Select(select) =>
+            select_rows(*select, schema, snapshot)?
+                .into_iter()
+                .map(|row| row.into_iter().map(Some).collect())
+                .collect(),
+    };
+
+    // Validate every row before writing anything
+    let mut rows: MyVec<MyVec<Value>> = MyVec::new();
+    for values in values_rows.iter() {
+        if values.len() != targets.len() {
+            return Err(
+                format!("INSERT has {} values but {} columns are expected", values.len(), targets.len())
+            );
+        }
+        let mut row = defaults.clone();
+        for (value, target) in values.iter().zip(targets.iter()) {
+            if let Some(value) = value {
+                let column = &columns[*target];
+                row.as_mut_slice()[*target - 1] = value
+                    .coerce(column.column_type)
+                    .map_err(|e| format!("{}.{}: {}", table, column.name, e))?;
+            }
+        }
+        for (value, column) in row.iter().zip(columns.iter().skip(1)) {
+            if value.is_null() && !column.nullable {
+                return Err(format!("Column {}.{} cannot be NULL", table, column.name));
+            }
+        }
+        rows.push(row);
+    }
+
+    let xid = statement_xid(snapshot)?;
+    // Keys of all the rows are reserved at once
+    let first_id = if rows.len() > 0 { reserve_pk_sequence(schema.name.as_str(), &table, rows.len())? } else { 0 };
+    let mut lines: MyVec<MyVec<Value>> = MyVec::new();
+    for (offset, row) in rows.iter().enumerate() {
+        let mut line = MyVec::new();
+        line.push(Value::Integer(first_id + (offset as i64)));
+        for value in row.iter() {
+            line.push(value.clone());
+        }
+        lines.push(line);
+    }
+    check_foreign_keys(schema, &table, &MyVec::new(), &lines, xid)?;
+    let mut indexes = table_indexes(schema, &table)?;
+    let mut writes = MyVec::new();
+    add_rows(&table, &lines, &mut writes, &mut indexes, xid, schema)?;
+    apply_writes(schema, writes, indexes.iter_mut())?;
+
+    let mut returned = Vec::new();
+    for line in lines.iter() {
+        if returning.len() > 0 {
+            let mut inserted: Row = MyHashMap::new();
+            for (column, value) in columns.iter().zip(line.iter()) {
+                inserted.insert(format!("{}.{}", table, column.name), value.clone());
+            }
+            let mut returned_row = Vec::new();
+            for output in returning.iter() {
+                returned_row.push(evaluate_row(output, &inserted, schema, snapshot)?);
+            }
+            returned.push(returned_row);
+        }
+    }
+    if returning.len() > 0 { Ok(Some(returned)) } else { Ok(None) }
+}
+
+fn execute_delete(mut query: DeleteQuery, schema: &Schema, snapshot: &Snapshot) -> DbResponse {
+    let scope = match Scope::single(&query.table, schema, snapshot) {
+        Ok(scope) => scope,
+        Err(e) => {
+            return DbResponse::Error(e);
+        }
+    };
+    if let Err(e) = prepare_condition(&mut query.condition, &scope) {
+        return DbResponse::Error(e);
+    }
+    let matching = match matching_rows(&query.table, &query.condition, schema, snapshot) {
+        Ok(matching) => matching,
+        Err(e) => {
+            return DbResponse::Error(e);
+        }
+    };
+
+    let xid = match statement_xid(snapshot) {
+        Ok(xid) => xid,
+        Err(e) => {
+            return DbResponse::Error(e);
+        }
+    };
+    // Rows matching the condition, then the rows that reference them
+    let columns = &schema.structure[&query.table];
+    let mut matched = MyVec::new();
+    let scanned = scan_table_versions(&query.table, schema, |id, version, row| {
+        if snapshot.sees_version(&version) && matching.matches(&query.condition, id, row) {
+            delete_version(&query.table, version, xid, schema)?;
+            matched.push((id, row_values(&query.table, columns, row)));
+        }
+        Ok(())
+    });
+    let mut plan = DeletePlan { tables: MyVec::new(), restricted: MyVec::new() };
+    let result = scanned
+        .and_then(|_| plan.delete(schema, snapshot, &query.table, &matched, xid))
+        .and_then(|_| plan.check_restricted(schema, xid))
+        .and_then(|_| plan.apply(schema, xid));
+    match result {
+        Ok(()) => DbResponse::Success(None),
+        Err(e) => DbResponse::Error(e),
+    }
+}
+
+// Rows a DELETE removes table by table, with the rows of other tables its
+// foreign keys remove or set to NULL. Everything is planned before anything
+// is written, so a RESTRICT failure leaves every table alone.
+struct DeletePlan {
+    tables: MyVec<TablePlan>,
+    // RESTRICT foreign keys with the deleted rows they may not refer to,
+    // checked once every row the statement deletes is known
+    restricted: MyVec<(Reference, MyVec<MyVec<Value>>)>,
+}
+
+// The row versions of a table to change, by the hash keys of their RowId
+struct TablePlan {
+    table: String,
+    deleted: MyHashMap<String, ()>,
+    // Rows to keep with some columns set to NULL, and the positions of those columns
+    nulled: MyHashMap<String, MyVec<usize>>,
+}
+
+impl DeletePlan {
+    fn table(&mut self, table: &str) -> &mut TablePlan {
+        let position = match self.tables.iter().position(|plan| plan.table == table) {
+            Some(position) => position,
+            None => {
+                self.tables.push(TablePlan {
+                    table: table.to_string(),
+                    deleted: MyHashMap::new(),
+                    nulled: MyHashMap::new(),
+                });
+                self.tables.len() - 1
+            }
+        };
+        &mut self.tables[position]
+    }
+
+    fn delete(
+        &mut self,
+        schema: &Schema,
+        snapshot: &Snapshot,
+        table: &str,
+        rows: &MyVec<(RowId, MyVec<Value>)>,
+        xid: u64
+    ) -> Result<(), String> {
+        let plan = self.table(table);
+        let mut deleted = MyVec::new();
+        for (id, values) in rows.iter() {
+            let id = id.hash_key();
+            if plan.deleted.get(&id).is_none() {
+                plan.deleted.insert(id, ());
+                deleted.push(values.clone());
+            }
+        }
+        if deleted.len() == 0 {
+            return Ok(());
+        }
+
+        for reference in references_to(schema, table)?.iter() {
+            match reference.on_delete {
+                OnDelete::Restrict => self.restricted.push((reference.clone(), deleted.clone())),
+                OnDelete::Cascade => {
+                    let children = reference.referencing(schema, snapshot, &deleted, xid)?;
+                    self.delete(schema, snapshot, &reference.table, &children, xid)?;
+                }
+                OnDelete::SetNull => {
+                    let children = reference.referencing(schema, snapshot, &deleted, xid)?;
+                    let plan = self.table(&reference.table);
+                    for (id, _) in children.iter() {
+                        let id = id.hash_key();
+                        match plan.nulled.get_mut(&id) {
+                            Some(positions) => positions.extend(reference.positions().iter().copied()),
+                            None => plan.nulled.insert(id, reference.positions().clone()),
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Rows the statement deletes as well do not hold on to their parents
+    fn check_restricted(&self, schema: &Schema, xid: u64) -> Result<(), String> {
+        for (reference, parents) in self.restricted.iter() {
+            let deleted = self.tables
+                .iter()
+                .find(|plan| plan.table == reference.table)
+                .map(|plan| &plan.deleted);
+            reference.check_unreferenced(schema, parents, deleted, xid, "Delete")?;
+        }
+        Ok(())
+    }
+
+    // All the tables change in one write
+    fn apply(&self, schema: &Schema, xid: u64) -> Result<(), String> {
+        let mut writes = MyVec::new();
+        let mut all_indexes = MyVec::new();
+        for plan in self.tables.iter() {
+            let columns = &schema.structure[&plan.table];
+            let mut indexes = table_indexes(schema, &plan.table)?;
+            let mut nulled_rows = MyVec::new();
+            table_block_writes(&plan.table, schema, &mut writes, |id, version, row| {
+                let id = id.hash_key();
+                let deleted = plan.deleted.get(&id).is_some();
+                let nulled = plan.nulled.get(&id);
+                if !deleted && nulled.is_none() {
+                    return Ok(Some(version));
+                }
+                let mut values = row_values(&plan.table, columns, row);
+                for index in indexes.iter_mut() {
+                    index.delete(version, &values, xid);
+                }
+                let new_version = delete_version(&plan.table, version, xid, schema)?;
+                if let (false, Some(positions)) = (deleted, nulled) {
+                    for position in positions.iter() {
+                        values[*position] = Value::Null;
+                    }
+                    nulled_rows.push(values);
+                }
+                Ok(Some(new_version))
+            })?;
+            add_rows(&plan.table, &nulled_rows, &mut writes, &mut indexes, xid, schema)?;
+            all_indexes.push(indexes);
+        }
+        apply_writes(
+            schema,
+            writes,
+            all_indexes.iter_mut().flat_map(|indexes| indexes.iter_mut())
+        )
+    }
+}
+
+fn execute_update(mut query: UpdateQuery, schema: &Schema, snapshot: &Snapshot) -> DbResponse {
+    let table = query.table.as_str();
+    let columns = match schema.structure.get(table) {
+        Some(columns) => columns,
+        None => {
+            return DbResponse::Error("No such table in DB".to_string());
+        }
+    };
+    let scope = match Scope::single(table, schema, snapshot) {
+        Ok(scope) => scope,
+        Err(e) => {
+            return DbResponse::Error(e);
+        }
+    };
+    let mut matching = Matching::Evaluate;
+    if let Some(condition) = query.condition.as_mut() {
+        if let Err(e) = prepare_condition(condition, &scope) {
+            return DbResponse::Error(e);
+        }
+        matching = match matching_rows(table, condition, schema, snapshot) {
+            Ok(matching) => matching,
+            Err(e) => {
+                return DbResponse::Error(e);
+            }
+        };
+    }
+
+    // Resolve and type-check the new values once, before touching any block
+    let mut new_values: MyHashMap<String, Value> = MyHashMap::new();
+    for (field, raw) in query.assignments.iter() {
+        if field.table.as_ref().is_some_and(|field_table| field_table != table) {
+            return DbResponse::Error(format!("Column {} is not in table {}", field.key(), table));
+        }
+        let column = match columns.iter().position(|column| column.name == field.column) {
+            // The first column is the generated key that identifies the row
+            Some(0) => {
+                return DbResponse::Error(
+                    format!("Column {}.{} is generated and cannot be updated", table, field.column)
+                );
+            }
+            Some(index) => &columns[index],
+            None => {
+                return DbResponse::Error(format!("No such column: {}.{}", table, field.column));
+            }
+        };
+        let value = match raw.coerce(column.column_type) {
+            Ok(value) => value,
+            Err(e) => {
+                return DbResponse::Error(format!("{}.{}: {}", table, column.name, e));
+            }
+        };
+        if value.is_null() && !column.nullable {
+            return DbResponse::Error(format!("Column {}.{} cannot be NULL", table, column.name));
+        }
+        new_values.insert(format!("{}.{}", table, column.name), value);
+    }
+
+    let xid = match statement_xid(snapshot) {
+        Ok(xid) => xid,
+        Err(e) => {
+            return DbResponse::Error(e);
+        }
+    };
+    let mut indexes = match table_indexes(schema, table) {
+        Ok(indexes) => indexes,
+        Err(e) => {
+            return DbResponse::Error(e);
+        }
+    };
+    // The old versions are deleted, the new ones are added once every
+    // block has been rewritten
+    let mut old_rows: MyVec<MyVec<Value>> = MyVec::new();
+    let mut updated_rows: MyVec<MyVec<Value>> = MyVec::new();
+    let mut writes = MyVec::new();
+    let scanned = table_block_writes(table, schema, &mut writes, |id, version, row| {
+        if !snapshot.sees_version(&version) {
+            return Ok(Some(version));
+        }
+        if let Some(condition) = &query.condition {
+            if !matching.matches(condition, id, row) {
+                return Ok(Some(version));
+            }
+        }
+        let deleted = delete_version(table, version, xid, schema)?;
+        let values = row_values(table, columns, row);
+        for index in indexes.iter_mut() {
+            index.delete(version, &values, xid);
+        }
+        let mut updated = MyVec::new();
+        for column in columns.iter() {
+            let key = format!("{}.{}", table, column.name);
+            match new_values.get(&key) {
+                Some(value) => updated.push(value.clone()),
+                None => updated.push(row.get(&key).cloned().unwrap_or(Value::Null)),
+            }
+        }
+        old_rows.push(values);
+        updated_rows.push(updated);
+        Ok(Some(deleted))
+    });
+    // The old keys are given back before the new ones are taken, so rows
+    // can swap their keys
+    let result = scanned.and_then(|_| {
+        check_foreign_keys(schema, table, &old_rows, &updated_rows, xid)?;
+        add_rows(table, &updated_rows, &mut writes, &mut indexes, xid, schema)?;
+        apply_writes(schema, writes, indexes.iter_mut())
+    });
+    match result {
+        Ok(()) => DbResponse::Success(None),
+        Err(e) => DbResponse::Error(e),
+    }
+}
+
+// Transaction of a statement that writes, see transaction::Session::run
+fn statement_xid(snapshot: &Snapshot) -> Result<u64, String> {
+    snapshot.xid.ok_or_else(|| "The statement has no transaction to write in".to_string())
+}
+
+// Take the keys of the rows the transaction adds to a table in its
+// indexes, and add the writes of the new row versions and of the indexes
+// to the writes of the statement. Fails when a key is taken.
+fn add_rows(
+    table: &str,
+    rows: &MyVec<MyVec<Value>>,
+    writes: &mut MyVec<FileWrite>,
+    indexes: &mut MyVec<UniqueIndex>,
+    xid: u64,
+    schema: &Schema
+) -> Result<(), String> {
+    for index in indexes.iter_mut() {
+        for row in rows.iter() {
+            index.insert(row, xid).map_err(|e| format!("{}: {}", table, e))?;
+        }
+        writes.extend(index.write());
+    }
+    append_versions(table, rows, xid, schema, writes)
+}
+
+// Apply the writes of a statement as one change, the indexes then hold
+// what is stored
+fn apply_writes<'a>(
+    schema: &Schema,
+    writes: MyVec<FileWrite>,
+    indexes: impl Iterator<Item = &'a mut UniqueIndex>
+) -> Result<(), String> {
+    wal::apply(&schema.name, writes)?;
+    for index in indexes {
+        index.mark_stored();
+    }
+    Ok(())
+}
+
+// Add row versions created by the transaction to the blocks of a table
+// with room for them, new blocks are made as needed. A block the statement
+// already rewrites gets the rows at the end of its new content.
+fn append_versions(
+    table: &str,
+    rows: &MyVec<MyVec<Value>>,
+    xid: u64,
+    schema: &Schema,
+    writes: &mut MyVec<FileWrite>
+) -> Result<(), String> {
+    let limit = schema.tuples_limit.max(1) as usize;
+    let header = block_header(&schema.structure[table]);
+    let mut block = 0;
+    let mut position = 0;
+    while position < rows.len() {
+        let (next, stored) = find_not_full_csv(schema, table, block + 1)?;
+        block = next;
+        let count = limit.saturating_sub(stored).max(1).min(rows.len() - position);
+        let mut content = String::new();
+        for values in rows[position..position + count].iter() {
+            content.push_str(&encode_version(RowVersion { xmin: xid, xmax: 0 }, values));
+            content.push('\n');
+        }
+        position += count;
+
+        let path = format!("{}/{}/{}.csv", schema.name, table, block);
+        match writes.iter_mut().find(|write| write.path() == path) {
+            Some(write) => write.extend(content.as_bytes()),
+            None if Path::new(&path).exists() => writes.push(FileWrite::append(path, content.into_bytes())),
+            None => writes.push(FileWrite::replace(path, format!("{}\n{}", header, content).into_bytes())),
+        }
+    }
+    Ok(())
+}
+
+// Mark a visible row version deleted by the transaction. A version another
+// transaction has deleted, whether or not it has committed yet, can only
+// change again if that transaction rolled back.
+fn delete_version(table: &str, version: RowVersion, xid: u64, schema: &Schema) -> Result<RowVersion, String> {
+    if version.xmax != 0 && !is_aborted(&schema.name, version.xmax) {
+        return Err(format!("Could not serialize: a row of {} was changed by a concurrent transaction", table));
+    }
+    Ok(RowVersion { xmax: xid, ..version })
+}
+
+// Resolve the WHERE of UPDATE or DELETE against the table
+fn prepare_condition(condition: &mut Expr, scope: &Scope) -> Result<(), String> {
+    resolve_expr(condition, scope)?;
+    if contains_aggregate(condition) {
+        return Err("Aggregate functions are not allowed in WHERE".to_string());
+    }
+    prepare_subqueries(condition, scope.schema, scope.snapshot)
+}
+
+// How UPDATE and DELETE decide which rows of a block to change
+enum Matching {
+    // The condition is checked for each row while the blocks are rewritten
+    Evaluate,
+    // Hash keys of where the matching row versions are stored, found
+    // before rewriting since correlated subqueries can fail halfway
+    Keys(MyHashMap<String, ()>),
+}
+
+impl Matching {
+    fn matches(&self, condition: &Expr, id: RowId, row: &Row) -> bool {
+        match self {
+            Matching::Evaluate => execute_conditions(condition, row),
+            Matching::Keys(keys) => keys.get(&id.hash_key()).is_some(),
+        }
+    }
+}
+
+fn matching_rows(table: &str, condition: &Expr, schema: &Schema, snapshot: &Snapshot) -> Result<Matching, String> {
+    if !has_subquery(condition) {
+        return Ok(Matching::Evaluate);
+    }
+    let mut keys = MyHashMap::new();
+    scan_table_versions(table, schema, |id, version, row| {
+        if snapshot.sees_version(&version) && evaluate_row(condition, row, schema, snapshot)? == Value::Boolean(true) {
+            keys.insert(id.hash_key(), ());
+        }
+        Ok(())
+    })?;
+    Ok(Matching::Keys(keys))
+}
+
+fn execute_select(query: SelectQuery, schema: &Schema, snapshot: &Snapshot) -> DbResponse {
+    match select_rows(query, schema, snapshot) {
+        Ok(rows) => DbResponse::Success(Some(rows)),
+        Err(e) => DbResponse::Error(e),
+    }
+}
+
+fn select_rows(mut query: SelectQuery, schema: &Schema, snapshot: &Snapshot) -> Result<Vec<Vec<Value>>, String> {
+    let scope = resolve_select(&mut query, schema, snapshot, None)?;
+
+    let mut outputs = output_exprs(&query.columns, &scope);
+
+    // Aggregates are computed once per group and referred to by their
+    // position through `#agg.N` columns of the group rows
+    let mut aggregates: MyVec<Expr> = MyVec::new();
+    for output in outputs.iter_mut() {
+        extract_aggregates(output, &mut aggregates)?;
+    }
+    if let Some(having) = query.having.as_mut() {
+        extract_aggregates(having, &mut aggregates)?;
+    }
+    for item in query.order_by.iter_mut() {
+        extract_aggregates(&mut item.expr, &mut aggregates)?;
+    }
+    let grouped = query.group_by.len() > 0 || aggregates.len() > 0 || query.having.is_some();
+    if grouped {
+        for output in outputs.iter() {
+            check_grouped(output, &query.group_by)?;
+        }
+        if let Some(having) = &query.having {
+            check_grouped(having, &query.group_by)?;
+        }
+        for item in query.order_by.iter() {
+            check_grouped(&item.expr, &query.group_by)?;
+        }
+    }
+
+    // Subqueries that do not depend on the current row are run once here,
+    // correlated ones are run for every row of the select list, WHERE and HAVING
+    for output in outputs.iter_mut() {
+        prepare_subqueries(output, schema, snapshot)?;
+    }
+    for expr in query.exprs_mut().iter_mut() {
+        prepare_subqueries(expr, schema, snapshot)?;
+    }
+    let mut row_independent: MyVec<&Expr> = aggregates.iter().collect();
+    for item in query.from.iter() {
+        for join in item.joins.iter() {
+            row_independent.extend(join.condition.iter());
+        }
+    }
+    row_independent.extend(query.group_by.iter());
+    row_independent.extend(query.order_by.iter().map(|item| &item.expr));
+    if row_independent.iter().any(|expr| has_subquery(expr)) {
+        return Err(
+            "Correlated subqueries are only supported in the select list, WHERE and HAVING".to_string()
+        );
+    }
+
+    let mut filtered_data = join_tables(&query.from, query.condition.as_ref(), &scope)?;
+    if grouped {
+        filtered_data = aggregate_rows(&filtered_data, &query.group_by, &aggregates)?;
+        if let Some(having) = &query.having {
+            filtered_data = filter_rows(&filtered_data, having, schema, snapshot)?;
+        }
+    }
+
+    let offset = query.offset.unwrap_or(0);
+    let limit = query.limit.unwrap_or(usize::MAX);
+    let positions: MyVec<usize> = if query.order_by.len() > 0 {
+        // Duplicates removed by DISTINCT would not count towards the limit
+        let keep = if query.distinct {
+            None
+        } else {
+            query.limit.map(|limit| offset.saturating_add(limit))
+        };
+        sort_positions(&filtered_data, &query.order_by, keep)
+    } else {
+        (0..filtered_data.len()).collect()
+    };
+
+    // Projected rows already returned by SELECT DISTINCT, by hash key
+    let mut seen: MyHashMap<String, ()> = MyHashMap::new();
+    let mut skipped = 0;
+    let mut result_matrix = Vec::new();
+    for position in positions.iter() {
+        if result_matrix.len() >= limit {
+            break;
+        }
+        let row = &filtered_data[*position];
+        let mut selected_row: MyVec<Value> = MyVec::new();
+        for output in outputs.iter() {
+            selected_row.push(evaluate_row(output, row, schema, snapshot)?);
+        }
+        if query.distinct {
+            let key = group_key(&selected_row);
+            if seen.get(&key).is_some() {
+                continue;
+            }
+            seen.insert(key, ());
+        }
+        if skipped < offset {
+            skipped += 1;
+            continue;
+        }
+        result_matrix.push(selected_row.iter().cloned().collect());
+    }
+    Ok(result_matrix)
+}
+
+// Replace every aggregate call in the expression with a reference to its
+// result, identical calls share one result
+fn extract_aggregates(expr: &mut Expr, aggregates: &mut MyVec<Expr>) -> Result<(), String> {
+    match expr {
+        Expr::Aggregate { function, argument, .. } => {
+            if argument.as_ref().is_some_and(|argument| contains_aggregate(argument)) {
+                return Err(format!("Aggregate calls cannot be nested in {}", function));
+            }
+            let index = match aggregates.iter().position(|aggregate| aggregate == expr) {
+                Some(index) => index,
+                None => {
+                    aggregates.push(expr.clone());
+                    aggregates.len() - 1
+                }
+            };
+            *expr = Expr::Column(ColumnRef {
+                table: Some("#agg".to_string()),
+                column: index.to_string(),
+            });
+            Ok(())
+        }
+        _ => {
+            for child in expr.children_mut().iter_mut() {
+                extract_aggregates(child, aggregates)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+fn contains_aggregate(expr: &Expr) -> bool {
+    match expr {
+        Expr::Aggregate { .. } => true,
+        _ => expr.children().iter().any(|child| contains_aggregate(child)),
+    }
+}
+
+// In a grouped query columns can only be used through GROUP BY expressions
+// or inside aggregates
+fn check_grouped(expr: &Expr, group_by: &MyVec<Expr>) -> Result<(), String> {
+    if group_by.iter().any(|group| group == expr) {
+        return Ok(());
+    }
+    match expr {
+        Expr::Column(column) if column.table.as_deref() == Some("#agg") => Ok(()),
+        Expr::Column(column) =>
+            Err(
+                format!(
+                    "Column {} must appear in GROUP BY or be used in an aggregate function",
+                    column.key()
+                )
+            ),
+        _ => {
+            for child in expr.children().iter() {
+                check_grouped(child, group_by)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+// Streaming hash aggregation: every row is routed to its group through a
+// hash table and folded into the group's accumulators. The result has one
+// row per group, holding the columns of the group's first row and the
+// aggregate results under `#agg.N`.
+fn aggregate_rows(
+    rows: &MyVec<Row>,
+    group_by: &MyVec<Expr>,
+    aggregates: &MyVec<Expr>
+) -> Result<MyVec<Row>, String> {
+    let new_accumulators = || -> MyVec<Accumulator> {
+        aggregates
+            .iter()
+            .map(|aggregate| {
+                match aggregate {
+                    Expr::Aggregate { function, distinct, .. } => Accumulator::new(*function, *distinct),
+                    _ => unreachable!(),
+                }
+            })
+            .collect()
+    };
+
+    let mut group_index: MyHashMap<String, usize> = MyHashMap::new();
+    let mut groups: MyVec<Row> = MyVec::new();
+    let mut accumulators: MyVec<MyVec<Accumulator>> = MyVec::new();
+    for row in rows.iter() {
+        let values: MyVec<Value> = group_by
+            .iter()
+            .map(|group| evaluate(group, row))
+            .collect();
+        let key = group_key(&values);
+        let index = match group_index.get(&key) {
+            Some(index) => *index,
+            None => {
+                group_index.insert(key, groups.len());
+                groups.push(row.clone());
+                accumulators.push(new_accumulators());
+                groups.len() - 1
+            }
+        };
+        for (i, aggregate) in aggregates.iter().enumerate() {
+            let argument = match aggregate {
+                Expr::Aggregate { argument: Some(argument), .. } => Some(evaluate(argument, row)),
+                _ => None,
+            };
+            accumulators[index][i].update(argument.as_ref())?;
+        }
+    }
+    // Without GROUP BY there is exactly one group, even for no rows
+    if group_by.len() == 0 && groups.len() == 0 {
+        groups.push(Row::new());
+        accumulators.push(new_accumulators());
+    }
+
+    for (i, group) in groups.iter_mut().enumerate() {
+        for (j, accumulator) in accumulators[i].iter().enumerate() {
+            group.insert(format!("#agg.{}", j), accumulator.finish());
+        }
+    }
+    Ok(groups)
+}
+
+// Register the FROM tables and qualify every column reference of the query
+// with the table (or alias) it belongs to
+fn resolve_select<'a>(
+    query: &mut SelectQuery,
+    schema: &'a Schema,
+    snapshot: &'a Snapshot,
+    outer: Option<&'a Scope<'a>>
+) -> Result<Scope<'a>, String> {
+    let mut scope = Scope::new(schema, snapshot, outer);
+    for item in query.from.iter() {
+        scope.add(&item.table)?;
+        for join in item.joins.iter() {
+            scope.add(&join.table)?;
+        }
+    }
+
+    resolve_select_items(&mut query.columns, &scope)?;
+    // ORDER BY may refer to output columns by their aliases
+    for item in query.order_by.iter_mut() {
+        if let Expr::Column(ColumnRef { table: None, column }) = &item.expr {
+            let aliased = query.columns.iter().find_map(|output| {
+                match output {
+                    SelectItem::Expr(expr, Some(alias)) if alias == column => Some(expr.clone()),
+                    _ => None,
+                }
+            });
+            if let Some(expr) = aliased {
+                item.expr = expr;
+            }
+        }
+    }
+    for item in query.from.iter_mut() {
+        for join in item.joins.iter_mut() {
+            if let Some(condition) = join.condition.as_mut() {
+                resolve_expr(condition, &scope)?;
+                if contains_aggregate(condition) {
+                    return Err("Aggregate functions are not allowed in JOIN conditions".to_string());
+                }
+            }
+        }
+    }
+    if let Some(condition) = query.condition.as_mut() {
+        resolve_expr(condition, &scope)?;
+        if contains_aggregate(condition) {
+            return Err("Aggregate functions are not allowed in WHERE".to_string());
+        }
+    }
+    for group in query.group_by.iter_mut() {
+        resolve_expr(group, &scope)?;
+        if contains_aggregate(group) {
+            return Err("Aggregate functions are not allowed in GROUP BY".to_string());
+        }
+    }
+    if let Some(having) = query.having.as_mut() {
+        resolve_expr(having, &scope)?;
+    }
+    for item in query.order_by.iter_mut() {
+        if !is_resolved(&item.expr) {
+            resolve_expr(&mut item.expr, &scope)?;
+        }
+    }
+    Ok(scope)
+}
+
+// Tables visible to a query, each under its binding: the alias if it has
+// one, the table name otherwise
+struct Scope<'a> {
+    schema: &'a Schema,
+    // Row versions the query sees
+    snapshot: &'a Snapshot,
+    bindings: MyVec<(String, String)>,
+    // Scope of the enclosing query for subqueries
+    outer: Option<&'a Scope<'a>>,
+}
+
+impl<'a> Scope<'a> {
+    fn new(schema: &'a Schema, snapshot: &'a Snapshot, outer: Option<&'a Scope<'a>>) -> Scope<'a> {
+        Scope { schema, snapshot, bindings: MyVec::new(), outer }
+    }
+
+    // Scope of UPDATE and DELETE, which work on one table
+    fn single(table: &str, schema: &'a Schema, snapshot: &'a Snapshot) -> Result<Scope<'a>, String> {
+        let mut scope = Scope::new(schema, snapshot, None);
+        scope.add(&TableRef { name: table.to_string(), alias: None })?;
+        Ok(scope)
+    }
+
+    fn add(&mut self, table: &TableRef) -> Result<(), String> {
+        if !self.schema.structure.contains_key(&table.name) {
+            return Err(format!("No such table in DB: {}", table.name));
+        }
+        let binding = table.binding();
+        if self.table_of(binding).is_some() {
+            return Err(format!("Table name {} specified more than once", binding));
+        }
+        self.bindings.push((binding.to_string(), table.name.clone()));
+        Ok(())
+    }
+
+    fn table_of(&self, binding: &str) -> Option<&str> {
+        self.bindings
+            .iter()
+            .find(|(b, _)| b == binding)
+            .map(|(_, table)| table.as_str())
+    }
+
+    fn columns(&self, binding: &str) -> &'a [Column] {
+        match self.table_of(binding).and_then(|table| self.schema.structure.get(table)) {
+            Some(columns) => columns,
+            None => &[],
+        }
+    }
+
+    fn column_type(&self, column: &ColumnRef) -> Option<ColumnType> {
+        self.columns(column.table.as_ref()?)
+            .iter()
+            .find(|c| c.name == column.column)
+            .map(|c| c.column_type)
+    }
+
+    // Qualify a bare column with the only table that has it and check that
+    // a qualified one exists. Columns not found here are looked up in the
+    // enclosing queries, the result is how many levels up the column was found.
+    fn resolve(&self, column: &mut ColumnRef) -> Result<usize, String> {
+        if let Some(binding) = &column.table {
+            if self.table_of(binding).is_none() {
+                if let Some(outer) = self.outer {
+                    return outer.resolve(column).map(|depth| depth + 1);
+                }
+            }
+            if self.column_type(column).is_none() {
+                return Err(format!("No such column: {}", column.key()));
+            }
+            return Ok(0);
+        }
+        let mut found: Option<&str> = None;
+        for (binding, _) in self.bindings.iter() {
+            if self.columns(binding).iter().any(|c| c.name == column.column) {
+                if found.is_some() {
+                    return Err(format!("Column reference {} is ambiguous", column.column));
+                }
+                found = Some(binding);
+            }
+        }
+        match (found, self.outer) {
+            (Some(binding), _) => {
+                column.table = Some(binding.to_string());
+                Ok(0)
+            }
+            (None, Some(outer)) => outer.resolve(column).map(|depth| depth + 1),
+            (None, None) => Err(format!("No such column: {}", column.column)),
+        }
+    }
+}
+
+fn resolve_expr(expr: &mut Expr, scope: &Scope) -> Result<(), String> {
+    match expr {
+        Expr::Column(column) => {
+            let depth = scope.resolve(column)?;
+            if depth > 0 {
+                *expr = Expr::OuterColumn(column.clone(), depth);
+            }
+            Ok(())
+        }
+        Expr::Exists(subquery) | Expr::Subquery(subquery) => {
+            resolve_select(subquery, scope.schema, scope.snapshot, Some(scope))?;
+            Ok(())
+        }
+        Expr::InSubquery(left, subquery, _) => {
+            resolve_expr(left, scope)?;
+            resolve_select(subquery, scope.schema, scope.snapshot, Some(scope))?;
+            Ok(())
+        }
+        _ => {
+            for child in expr.children_mut().iter_mut() {
+                resolve_expr(child, scope)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+// Whether every column in the expression is already qualified
+fn is_resolved(expr: &Expr) -> bool {
+    match expr {
+        Expr::Column(column) => column.table.is_some(),
+        _ => expr.children().iter().all(|child| is_resolved(child)),
+    }
+}
+
+fn resolve_select_items(items: &mut MyVec<SelectItem>, scope: &Scope) -> Result<(), String> {
+    for item in items.iter_mut() {
+        match item {
+            SelectItem::Wildcard => {}
+            SelectItem::TableWildcard(binding) => {
+                if scope.table_of(binding).is_none() {
+                    return Err(format!("Table {} is not in FROM", binding));
+                }
+            }
+            SelectItem::Expr(expr, _) => resolve_expr(expr, scope)?,
+        }
+    }
+    Ok(())
+}
+
+// Output expressions in the order they were requested, wildcards expanded
+fn output_exprs(items: &MyVec<SelectItem>, scope: &Scope) -> MyVec<Expr> {
+    let mut outputs: MyVec<Expr> = MyVec::new();
+    for item in items.iter() {
+        match item {
+            SelectItem::Wildcard => {
+                for (binding, _) in scope.bindings.iter() {
+                    push_table_columns(binding, scope, &mut outputs);
+                }
+            }
+            SelectItem::TableWildcard(binding) => {
+                push_table_columns(binding, scope, &mut outputs);
+            }
+            SelectItem::Expr(expr, _) => outputs.push(expr.clone()),
+        }
+    }
+    outputs
+}
+
+fn push_table_columns(binding: &str, scope: &Scope, outputs: &mut MyVec<Expr>) {
+    for column in scope.columns(binding).iter() {
+        outputs.push(
+            Expr::Column(ColumnRef {
+                table: Some(binding.to_string()),
+                column: column.name.clone(),
+            })
+        );
+    }
+}
+
+// Sort key of a row, rows that compare equal keep their scan order
+struct SortKey<'a> {
+    values: MyVec<Value>,
+    position: usize,
+    order_by: &'a MyVec<OrderItem>,
+}
+
+impl Ord for SortKey<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        for (i, item) in self.order_by.iter().enumerate() {
+            let ordering = self.values[i].sort_cmp(&other.values[i]);
+            let ordering = if item.descending { ordering.reverse() } else { ordering };
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+        self.position.cmp(&other.position)
+    }
+}
+
+impl PartialOrd for SortKey<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for SortKey<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for SortKey<'_> {}
+
+// Positions of the rows in ORDER BY order. With `keep` only that many first
+// rows are needed, so they are selected through a bounded max-heap instead
+// of sorting everything.
+fn sort_positions(rows: &MyVec<Row>, order_by: &MyVec<OrderItem>, keep: Option<usize>) -> MyVec<usize> {
+    let sort_key = |position: usize| SortKey {
+        values: order_by
+            .iter()
+            .map(|item| evaluate(&item.expr, &rows[position]))
+            .collect(),
+        position,
+        order_by,
+    };
+
+    let mut keys: MyVec<SortKey> = match keep {
+        Some(keep) => {
+            let mut heap = BinaryHeap::new();
+            for position in 0..rows.len() {
+                let key = sort_key(position);
+                if heap.len() < keep {
+                    heap.push(key);
+                } else if heap.peek().is_some_and(|largest| key < *largest) {
+                    heap.pop();
+                    heap.push(key);
+                }
+            }
+            heap.into_iter().collect()
+        }
+        None => (0..rows.len()).map(sort_key).collect(),
+    };
+    keys.as_mut_slice().sort_unstable();
+    keys.iter()
+        .map(|key| key.position)
+        .collect()
+}
+
+struct Relation {
+    tables: MyVec<String>,
+    rows: MyVec<Row>,
+}
+
+// Join the entries of a FROM clause and filter them with the WHERE condition.
+// Conditions on a single entry are checked right after reading it, `a.x = b.y`
+// conditions between entries become hash joins and the rest are checked as
+// soon as all the tables they refer to are joined.
+fn join_tables(
+    from: &MyVec<FromItem>,
+    condition: Option<&Expr>,
+    scope: &Scope
+) -> Result<MyVec<Row>, String> {
+    let mut conjuncts: MyVec<&Expr> = MyVec::new();
+    if let Some(condition) = condition {
+        split_conjuncts(condition, &mut conjuncts);
+    }
+    let mut applied: MyVec<bool> = conjuncts
+        .iter()
+        .map(|_| false)
+        .collect();
+
+    let mut pending: MyVec<Option<Relation>> = MyVec::new();
+    for item in from.iter() {
+        let mut relation = read_from_item(item, scope)?;
+        apply_covered_conjuncts(&mut relation, &conjuncts, &mut applied);
+        pending.push(Some(relation));
+    }
+
+    let mut joined = pending[0].take().unwrap();
+    for _ in 1..pending.len() {
+        // Prefer a table that can be hash joined with what is already joined
+        let mut chosen = None;
+        let mut left_columns = MyVec::new();
+        let mut right_columns = MyVec::new();
+        let mut used = MyVec::new();
+        for (i, candidate) in pending.iter().enumerate() {
+            let candidate = match candidate {
+                Some(candidate) => candidate,
+                None => {
+                    continue;
+                }
+            };
+            for (j, conjunct) in conjuncts.iter().enumerate() {
+                if applied[j] {
+                    continue;
+                }
+                if let Some((left, right)) = equi_join_columns(conjunct, &joined, candidate, scope) {
+                    left_columns.push(left);
+                    right_columns.push(right);
+                    used.push(j);
+                }
+            }
+            if used.len() > 0 {
+                chosen = Some(i);
+                break;
+            }
+        }
+        let chosen = match chosen {
+            Some(i) => i,
+            None => pending
+                .iter()
+                .position(|candidate| candidate.is_some())
+                .unwrap(),
+        };
+
+        let next = pending[chosen].take().unwrap();
+        joined.rows = if used.len() > 0 {
+            hash_join(&joined.rows, &next.rows, &left_columns, &right_columns)
+        } else {
+            cartesian_product(&joined.rows, &next.rows)
+        };
+        for table in next.tables.iter() {
+            joined.tables.push(table.clone());
+        }
+        for j in used.iter() {
+            applied[*j] = true;
+        }
+        apply_covered_conjuncts(&mut joined, &conjuncts, &mut applied);
+    }
+
+    // Conditions that could not be placed on specific tables
+    let mut rows = joined.rows;
+    for (i, conjunct) in conjuncts.iter().enumerate() {
+        if !applied[i] {
+            rows = filter_rows(&rows, conjunct, scope.schema, scope.snapshot)?;
+        }
+    }
+    Ok(rows)
+}
+
+fn read_table(table: &TableRef, scope: &Scope) -> Result<Relation, String> {
+    let mut tables = MyVec::new();
+    tables.push(table.binding().to_string());
+    Ok(Relation {
+        tables,
+        rows: read_all_table_data(&table.name, table.binding(), scope.schema, scope.snapshot)?,
+    })
+}
+
+// Read a FROM entry, applying its explicit joins from left to right
+fn read_from_item(item: &FromItem, scope: &Scope) -> Result<Relation, String> {
+    let mut relation = read_table(&item.table, scope)?;
+    for join in item.joins.iter() {
+        let right = read_table(&join.table, scope)?;
+        relation = execute_join(relation, right, join.kind, join.condition.as_ref(), scope);
+    }
+    Ok(relation)
+}
+
+// Nested loop join, the ON equalities between the two sides narrow the loop
+// down to a hash table lookup. Outer joins pad unmatched rows with NULLs.
+fn execute_join(
+    left: Relation,
+    right: Relation,
+    kind: JoinKind,
+    condition: Option<&Expr>,
+    scope: &Scope
+) -> Relation {
+    let mut conjuncts: MyVec<&Expr> = MyVec::new();
+    if let Some(condition) = condition {
+        split_conjuncts(condition, &mut conjuncts);
+    }
+    let mut left_columns = MyVec::new();
+    let mut right_columns = MyVec::new();
+    let mut residual: MyVec<&Expr> = MyVec::new();
+    for conjunct in conjuncts.iter() {
+        match equi_join_columns(conjunct, &left, &right, scope) {
+            Some((left_column, right_column)) => {
+                left_columns.push(left_column);
+                right_columns.push(right_column);
+            }
+            None => residual.push(conjunct),
+        }
+    }
+
+    let buckets = if left_columns.len() > 0 {
+        Some(build_hash_table(&right.rows, &right_columns))
+    } else {
+        None
+    };
+    let all_right_rows: MyVec<usize> = (0..right.rows.len()).collect();
+    let mut right_matched: MyVec<bool> = right.rows
+        .iter()
+        .map(|_| false)
+        .collect();
+
+    let mut rows = MyVec::new();
+    for left_row in left.rows.iter() {
+        let candidates = match &buckets {
+            Some(buckets) => join_key(left_row, &left_columns).and_then(|key| buckets.get(&key)),
+            None => Some(&all_right_rows),
+        };
+        let mut matched = false;
+        for i in candidates.into_iter().flat_map(|candidates| candidates.iter()) {
+            let mut combined_row = left_row.clone();
+            combined_row.extend(right.rows[*i].clone());
+            if residual.iter().all(|conjunct| execute_conditions(conjunct, &combined_row)) {
+                matched = true;
+                right_matched[*i] = true;
+                rows.push(combined_row);
+            }
+        }
+        if !matched && kind == JoinKind::Left {
+            let mut combined_row = left_row.clone();
+            combined_row.extend(null_row(&right.tables, scope));
+            rows.push(combined_row);
+        }
+    }
+    if kind == JoinKind::Right {
+        for (i, right_row) in right.rows.iter().enumerate() {
+            if !right_matched[i] {
+                let mut combined_row = null_row(&left.tables, scope);
+                combined_row.extend(right_row.clone());
+                rows.push(combined_row);
+            }
+        }
+    }
+
+    let mut tables = left.tables;
+    for table in right.tables.iter() {
+        tables.push(table.clone());
+    }
+    Relation { tables, rows }
+}
+
+// Row with NULL in every column of the tables
+fn null_row(tables: &MyVec<String>, scope: &Scope) -> Row {
+    let mut row = Row::new();
+    for binding in tables.iter() {
+        for column in scope.columns(binding).iter() {
+            row.insert(format!("{}.{}", binding, column.name), Value::Null);
+        }
+    }
+    row
+}
+
+fn split_conjuncts<'a>(expr: &'a Expr, conjuncts: &mut MyVec<&'a Expr>) {
+    if let Expr::And(left, right) = expr {
+        split_conjuncts(left, conjuncts);
+        split_conjuncts(right, conjuncts);
+    } else {
+        conjuncts.push(expr);
+    }
+}
+
+fn apply_covered_conjuncts(
+    relation: &mut Relation,
+    conjuncts: &MyVec<&Expr>,
+    applied: &mut MyVec<bool>
+) {
+    for (i, conjunct) in conjuncts.iter().enumerate() {
+        if !applied[i] && is_covered(conjunct, &relation.tables) {
+            relation.rows = relation.rows
+                .iter()
+                .filter(|row| execute_conditions(conjunct, row))
+                .cloned()
+                .collect();
+            applied[i] = true;
+        }
+    }
+}
+
+// Whether every column in the expression belongs to one of the tables
+fn is_covered(expr: &Expr, tables: &MyVec<String>) -> bool {
+    match expr {
+        Expr::Column(column) => belongs_to(column, tables),
+        Expr::Aggregate { .. } => false,
+        // Subqueries are only checked once all the tables are joined
+        Expr::Exists(_) | Expr::Subquery(_) | Expr::InSubquery(..) => false,
+        _ => expr.children().iter().all(|child| is_covered(child, tables)),
+    }
+}
+
+fn belongs_to(column: &ColumnRef, tables: &MyVec<String>) -> bool {
+    match &column.table {
+        Some(table) => tables.iter().any(|t| t == table),
+        None => false,
+    }
+}
+
+// For `a.x = b.y` with `a` already joined and `b` being the candidate, the row
+// keys of both columns. Columns of different kinds are left to the filter,
+// since their values would not get equal hash keys.
+fn equi_join_columns(
+    conjunct: &Expr,
+    joined: &Relation,
+    candidate: &Relation,
+    scope: &Scope
+) -> Option<(String, String)> {
+    let (left, right) = match conjunct {
+        Expr::Compare(left, CompareOp::Eq, right) =>
+            match (left.as_ref(), right.as_ref()) {
+                (Expr::Column(left), Expr::Column(right)) => (left, right),
+                _ => {
+                    return None;
+                }
+            }
+        _ => {
+            return None;
+        }
+    };
+    let (joined_column, candidate_column) = if
+        belongs_to(left, &joined.tables) &&
+        belongs_to(right, &candidate.tables)
+    {
+        (left, right)
+    } else if belongs_to(right, &joined.tables) && belongs_to(left, &candidate.tables) {
+        (right, left)
+    } else {
+        return None;
+    };
+
+    let joined_type = scope.column_type(joined_column)?;
+    let candidate_type = scope.column_type(candidate_column)?;
+    if !joined_type.same_kind(candidate_type) {
+        return None;
+    }
+    Some((joined_column.key(), candidate_column.key()))
+}
+
+// Run the subqueries of the expression that do not depend on an enclosing
+// row and replace them with their results
+fn prepare_subqueries(expr: &mut Expr, schema: &Schema, snapshot: &Snapshot) -> Result<(), String> {
+    match expr {
+        Expr::Subquery(subquery) if !is_correlated(subquery) => {
+            let rows = select_rows((**subquery).clone(), schema, snapshot)?;
+            if rows.len() > 1 {
+                return Err("Scalar subquery returned more than one row".to_string());
+            }
+            let value = match rows.first() {
+                Some(row) if row.len() != 1 => {
+                    return Err("Subquery must return exactly one column".to_string());
+                }
+                Some(row) => row[0].clone(),
+                None => Value::Null,
+            };
+            *expr = Expr::Literal(value);
+        }
+        Expr::Exists(subquery) if !is_correlated(subquery) => {
+            let rows = select_rows((**subquery).clone(), schema, snapshot)?;
+            *expr = Expr::Literal(Value::Boolean(!rows.is_empty()));
+        }
+        Expr::InSubquery(left, subquery, negated) if !is_correlated(subquery) => {
+            prepare_subqueries(left, schema, snapshot)?;
+            let rows = select_rows((**subquery).clone(), schema, snapshot)?;
+            let mut list = MyVec::new();
+            for row in rows.into_iter() {
+                if row.len() != 1 {
+                    return Err("Subquery must return exactly one column".to_string());
+                }
+                list.push(Expr::Literal(row.into_iter().next().unwrap()));
+            }
+            let left = std::mem::replace(left.as_mut(), Expr::Literal(Value::Null));
+            *expr = Expr::InList(Box::new(left), list, *negated);
+        }
+        _ => {
+            for child in expr.children_mut().iter_mut() {
+                prepare_subqueries(child, schema, snapshot)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn has_subquery(expr: &Expr) -> bool {
+    match expr {
+        Expr::Exists(_) | Expr::Subquery(_) | Expr::InSubquery(..) => true,
+        _ => expr.children().iter().any(|child| has_subquery(child)),
+    }
+}
+
+// Whether the subquery refers to columns of the query it is part of
+fn is_correlated(subquery: &SelectQuery) -> bool {
+    subquery
+        .exprs()
+        .iter()
+        .any(|expr| references_outer(expr, 1))
+}
+
+// `level` is the number of subqueries between the expression and the query
+// whose columns are looked for
+fn references_outer(expr: &Expr, level: usize) -> bool {
+    match expr {
+        Expr::OuterColumn(_, depth) => *depth >= level,
+        Expr::Exists(subquery) | Expr::Subquery(subquery) =>
+            subquery
+                .exprs()
+                .iter()
+                .any(|expr| references_outer(expr, level + 1)),
+        Expr::InSubquery(left, subquery, _) =>
+            references_outer(left, level) ||
+                subquery
+                    .exprs()
+                    .iter()
+                    .any(|expr| references_outer(expr, level + 1)),
+        _ => expr.children().iter().any(|child| references_outer(child, level)),
+    }
+}
+
+// Replace the references of subqueries to the current query with the values of the row
+fn bind_outer(expr: &mut Expr, row: &Row, level: usize) {
+    match expr {
+        Expr::OuterColumn(column, depth) if *depth == level => {
+            *expr = Expr::Literal(row.get(&column.key()).cloned().unwrap_or(Value::Null));
+        }
+        Expr::Exists(subquery) | Expr::Subquery(subquery) => {
+            for expr in subquery.exprs_mut().iter_mut() {
+                bind_outer(expr, row, level + 1);
+            }
+        }
+        Expr::InSubquery(left, subquery, _) => {
+            bind_outer(left, row, level);
+            for expr in subquery.exprs_mut().iter_mut() {
+                bind_outer(expr, row, level + 1);
+            }
+        }
+        _ => {
+            for child in expr.children_mut().iter_mut() {
+                bind_outer(child, row, level);
+            }
+        }
+    }
+}
+
+// Evaluate an expression that may still hold correlated subqueries, they
+// are run with the values of the row
+fn evaluate_row(expr: &Expr, row: &Row, schema: &Schema, snapshot: &Snapshot) -> Result<Value, String> {
+    if !has_subquery(expr) {
+        return Ok(evaluate(expr, row));
+    }
+    let mut bound = expr.clone();
+    bind_outer(&mut bound, row, 0);
+    prepare_subqueries(&mut bound, schema, snapshot)?;
+    Ok(evaluate(&bound, row))
+}
+
+fn filter_rows(
+    rows: &MyVec<Row>,
+    condition: &Expr,
+    schema: &Schema,
+    snapshot: &Snapshot
+) -> Result<MyVec<Row>, String> {
+    let mut kept = MyVec::new();
+    for row in rows.iter() {
+        if evaluate_row(condition, row, schema, snapshot)? == Value::Boolean(true) {
+            kept.push(row.clone());
+        }
+    }
+    Ok(kept)
+}
+
+// A row passes the condition only when it evaluates to TRUE
+fn execute_conditions(condition: &Expr, data: &Row) -> bool {
+    evaluate(condition, data) == Value::Boolean(true)
+}
+
+fn evaluate(expr: &Expr, data: &Row) -> Value {
+    match expr {
+        Expr::Column(column) => data.get(&column.key()).cloned().unwrap_or(Value::Null),
+        Expr::Literal(value) => value.clone(),
+        Expr::Compare(left, operator, right) => {
+            let left = evaluate(left, data);
+            let right = evaluate(right, data);
+            if left.is_null() || right.is_null() {
+                return Value::Null;
+            }
+            Value::Boolean(compare_values(&left, *operator, &right))
+        }
+        // Three-valued logic: NULL stands for unknown
+        Expr::And(left, right) => {
+            let left = truth(&evaluate(left, data));
+            if left == Some(false) {
+                return Value::Boolean(false);
+            }
+            match (left, truth(&evaluate(right, data))) {
+                (_, Some(false)) => Value::Boolean(false),
+                (Some(true), Some(true)) => Value::Boolean(true),
+                _ => Value::Null,
+            }
+        }
+        Expr::Or(left, right) => {
+            let left = truth(&evaluate(left, data));
+            if left == Some(true) {
+                return Value::Boolean(true);
+            }
+            match (left, truth(&evaluate(right, data))) {
+                (_, Some(true)) => Value::Boolean(true),
+                (Some(false), Some(false)) => Value::Boolean(false),
+                _ => Value::Null,
+            }
+        }
+        Expr::Not(inner) =>
+            match truth(&evaluate(inner, data)) {
+                Some(value) => Value::Boolean(!value),
+                None => Value::Null,
+            }
+        Expr::IsNull(inner, negated) => Value::Boolean(evaluate(inner, data).is_null() != *negated),
+        Expr::Binary(left, operator, right) =>
+            apply_binary(&evaluate(left, data), *operator, &evaluate(right, data)),
+        Expr::Negate(inner) => negate(&evaluate(inner, data)),
+        Expr::Function(function, arguments) => {
+            let arguments: MyVec<Value> = arguments
+                .iter()
+                .map(|argument| evaluate(argument, data))
+                .collect();
+            call_function(*function, &arguments)
+        }
+        Expr::Case { operand, branches, otherwise } => {
+            let operand = operand.as_ref().map(|operand| evaluate(operand, data));
+            for (condition, result) in branches.iter() {
+                let matches = match &operand {
+                    Some(operand) => compare_values(operand, CompareOp::Eq, &evaluate(condition, data)),
+                    None => execute_conditions(condition, data),
+                };
+                if matches {
+                    return evaluate(result, data);
+                }
+            }
+            match otherwise {
+                Some(otherwise) => evaluate(otherwise, data),
+                None => Value::Null,
+            }
+        }
+        Expr::InList(left, list, negated) => {
+            let left = evaluate(left, data);
+            if left.is_null() {
+                return Value::Null;
+            }
+            let mut saw_null = false;
+            for item in list.iter() {
+                let item = evaluate(item, data);
+                if compare_values(&left, CompareOp::Eq, &item) {
+                    return Value::Boolean(!*negated);
+                }
+                saw_null = saw_null || item.is_null();
+            }
+            // `x IN (1, NULL)` is unknown rather than false when x is not 1
+            if saw_null { Value::Null } else { Value::Boolean(*negated) }
+        }
+        // Replaced with their results before rows are evaluated
+        Expr::Aggregate { .. } |
+        Expr::Exists(_) |
+        Expr::Subquery(_) |
+        Expr::InSubquery(..) |
+        Expr::OuterColumn(..) => Value::Null,
+    }
+}
+
+// Truth value of a condition, None when it is unknown
+fn truth(value: &Value) -> Option<bool> {
+    match value {
+        Value::Boolean(b) => Some(*b),
+        _ => None,
+    }
+}
+
+fn compare_values(left: &Value, operator: CompareOp, right: &Value) -> bool {
+    if left.is_null() || right.is_null() {
+        return false;
+    }
+    if operator == CompareOp::Like {
+        return like_match(&left.to_string(), &right.to_string());
+    }
+    match left.compare(right) {
+        Some(ordering) =>
+            match operator {
+                CompareOp::Eq => ordering == Ordering::Equal,
+                CompareOp::NotEq => ordering != Ordering::Equal,
+                CompareOp::Lt => ordering == Ordering::Less,
+                CompareOp::LtEq => ordering != Ordering::Greater,
+                CompareOp::Gt => ordering == Ordering::Greater,
+                CompareOp::GtEq => ordering != Ordering::Less,
+                CompareOp::Like => unreachable!(),
+            }
+        // Values that cannot be compared (e.g. a number against text) only differ
+        None => operator == CompareOp::NotEq,
+    }
+}
@@ -0,0 +1,148 @@
+use crate::MyVec;
+use crate::structs::{ BinaryOp, ScalarFunction };
+use crate::value::Value;
+
+// Number behind a value for arithmetic, text is accepted when it spells a number
+enum Number {
+    Integer(i64),
+    Real(f64),
+}
+
+fn to_number(value: &Value) -> Option<Number> {
+    match value {
+        Value::Integer(i) => Some(Number::Integer(*i)),
+        Value::Real(r) => Some(Number::Real(*r)),
+        Value::Text(s) => {
+            if let Ok(i) = s.trim().parse::<i64>() {
+                Some(Number::Integer(i))
+            } else {
+                s.trim().parse::<f64>().ok().map(Number::Real)
+            }
+        }
+        _ => None,
+    }
+}
+
+fn real(number: &Number) -> f64 {
+    match number {
+        Number::Integer(i) => *i as f64,
+        Number::Real(r) => *r,
+    }
+}
+
+// Arithmetic and concatenation. Like comparisons, operations on NULL give
+// NULL, and so do operations that have no meaningful result (division by
+// zero, arithmetic on non-numbers).
+pub fn apply_binary(left: &Value, operator: BinaryOp, right: &Value) -> Value {
+    if left.is_null() || right.is_null() {
+        return Value::Null;
+    }
+    if operator == BinaryOp::Concat {
+        return Value::Text(format!("{}{}", left, right));
+    }
+    let (left, right) = match (to_number(left), to_number(right)) {
+        (Some(left), Some(right)) => (left, right),
+        _ => {
+            return Value::Null;
+        }
+    };
+
+    if let (Number::Integer(a), Number::Integer(b)) = (&left, &right) {
+        let (a, b) = (*a, *b);
+        let result = match operator {
+            BinaryOp::Add => a.checked_add(b),
+            BinaryOp::Subtract => a.checked_sub(b),
+            BinaryOp::Multiply => a.checked_mul(b),
+            BinaryOp::Divide if b == 0 => {
+                return Value::Null;
+            }
+            BinaryOp::Divide => a.checked_div(b),
+            BinaryOp::Modulo if b == 0 => {
+                return Value::Null;
+            }
+            BinaryOp::Modulo => a.checked_rem(b),
+            BinaryOp::Concat => unreachable!(),
+        };
+        // On overflow the result is computed in floating point below
+        if let Some(result) = result {
+            return Value::Integer(result);
+        }
+    }
+
+    let (a, b) = (real(&left), real(&right));
+    let result = match operator {
+        BinaryOp::Add => a + b,
+        BinaryOp::Subtract => a - b,
+        BinaryOp::Multiply => a * b,
+        BinaryOp::Divide => a / b,
+        BinaryOp::Modulo => a % b,
+        BinaryOp::Concat => unreachable!(),
+    };
+    if result.is_finite() { Value::Real(result) } else { Value::Null }
+}
+
+pub fn negate(value: &Value) -> Value {
+    match to_number(value) {
+        Some(Number::Integer(i)) =>
+            match i.checked_neg() {
+                Some(i) => Value::Integer(i),
+                None => Value::Real(-(i as f64)),
+            }
+        Some(Number::Real(r)) => Value::Real(-r),
+        None => Value::Null,
+    }
+}
+
+// The number of arguments is checked by the parser
+pub fn call_function(function: ScalarFunction, arguments: &MyVec<Value>) -> Value {
+    match function {
+        ScalarFunction::Coalesce =>
+            arguments
+                .iter()
+                .find(|argument| !argument.is_null())
+                .cloned()
+                .unwrap_or(Value::Null),
+        // NULL arguments are skipped
+        ScalarFunction::Concat => {
+            let mut text = String::new();
+            for argument in arguments.iter().filter(|argument| !argument.is_null()) {
+                text.push_str(&argument.to_string());
+            }
+            Value::Text(text)
+        }
+        _ if arguments.iter().any(|argument| argument.is_null()) => Value::Null,
+        ScalarFunction::Upper => Value::Text(arguments[0].to_string().to_uppercase()),
+        ScalarFunction::Lower => Value::Text(arguments[0].to_string().to_lowercase()),
+        ScalarFunction::Length => Value::Integer(arguments[0].to_string().chars().count() as i64),
+        ScalarFunction::Substr => {
+            let text = arguments[0].to_string();
+            let start = match to_number(&arguments[1]) {
+                Some(Number::Integer(start)) => start,
+                _ => {
+                    return Value::Null;
+                }
+            };
+            // Positions are 1-based, the range may start before the text
+            let end = if arguments.len() > 2 {
+                match to_number(&arguments[2]) {
+                    Some(Number::Integer(length)) if length >= 0 => start.saturating_add(length),
+                    _ => {
+                        return Value::Null;
+                    }
+                }
+            } else {
+                i64::MAX
+            };
+            let substring = text
+                .chars()
+                .enumerate()
+                .filter(|(i, _)| {
+                    let position = (*i as i64) + 1;
+                    position >= start && position < end
+                })
+                .map(|(_, c)| c)
+                .collect();
+            Value::Text(substring)
+        }
+    }
+}
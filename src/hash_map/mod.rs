@@ -43,16 +43,16 @@ impl<K: AsRef<[u8]> + Clone + Eq, V: Clone> MyHashMap<K, V> {
         }
         self.size = 0; // Сбрасываем размер до 0, затем добавляем все элементы
 
-        for opt in old_buckets.iter() {
-            if let Some((key, value)) = opt {
-                self.insert(key.clone(), value.clone()); // Вставляем элементы в новую хеш-таблицу
-            }
+        for (key, value) in old_buckets.iter().flatten() {
+            self.insert(key.clone(), value.clone()); // Вставляем элементы в новую хеш-таблицу
         }
     }
 
     pub fn insert(&mut self, key: K, value: V) {
         const LOAD_FACTOR: f64 = 0.75;
-        if (self.size as f64) >= (self.buckets.len() as f64) * LOAD_FACTOR {
+        // Считаем с учётом нового элемента, чтобы в таблице всегда оставалось пустое ведро,
+        // иначе линейное пробирование в get() зациклится на отсутствующем ключе
+        if ((self.size + 1) as f64) > (self.buckets.len() as f64) * LOAD_FACTOR {
             self.rehash(); // Рехешируем при достижении порога
         }
 
@@ -102,7 +102,8 @@ impl<K: AsRef<[u8]> + Clone + Eq, V: Clone> MyHashMap<K, V> {
         None
     }
 
-    pub fn iter(&self) -> MyHashMapIter<K, V> {
+    #[allow(dead_code)]
+    pub fn iter(&self) -> MyHashMapIter<'_, K, V> {
         MyHashMapIter {
             map: self,
             index: 0,
@@ -110,10 +111,8 @@ impl<K: AsRef<[u8]> + Clone + Eq, V: Clone> MyHashMap<K, V> {
     }
 
     pub fn extend(&mut self, other: MyHashMap<K, V>) {
-        for bucket in other.buckets.iter() {
-            if let Some((key, value)) = bucket {
-                self.insert(key.clone(), value.clone());
-            }
+        for (key, value) in other.buckets.iter().flatten() {
+            self.insert(key.clone(), value.clone());
         }
     }
 }
@@ -121,15 +120,14 @@ impl<K: AsRef<[u8]> + Clone + Eq, V: Clone> MyHashMap<K, V> {
 impl<K: AsRef<[u8]> + Clone + Eq, V: Clone> Clone for MyHashMap<K, V> {
     fn clone(&self) -> Self {
         let mut new_map = MyHashMap::new();
-        for bucket in self.buckets.iter() {
-            if let Some((key, value)) = bucket {
-                new_map.insert(key.clone(), value.clone());
-            }
+        for (key, value) in self.buckets.iter().flatten() {
+            new_map.insert(key.clone(), value.clone());
         }
         new_map
     }
 }
 
+#[allow(dead_code)]
 pub struct MyHashMapIter<'a, K, V> {
     map: &'a MyHashMap<K, V>,
     index: usize,
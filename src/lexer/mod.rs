@@ -0,0 +1,227 @@
+use crate::MyVec;
+use crate::structs::SyntaxError;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenKind {
+    // Bare word, keywords are recognised by the parser case-insensitively
+    Ident(String),
+    // "quoted identifier", never treated as a keyword
+    QuotedIdent(String),
+    // 'string literal'
+    String(String),
+    Number(String),
+    Comma,
+    Dot,
+    LeftParen,
+    RightParen,
+    Star,
+    Semicolon,
+    Eq,
+    NotEq,
+    Lt,
+    LtEq,
+    Gt,
+    GtEq,
+    Plus,
+    Minus,
+    Slash,
+    Percent,
+    Concat,
+    Eof,
+}
+
+#[derive(Debug, Clone)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub line: usize,
+    pub column: usize,
+}
+
+struct Lexer {
+    chars: MyVec<char>,
+    pos: usize,
+    line: usize,
+    column: usize,
+}
+
+impl Lexer {
+    fn peek(&self) -> Option<char> {
+        self.peek_at(0)
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<char> {
+        if self.pos + offset < self.chars.len() { Some(self.chars[self.pos + offset]) } else { None }
+    }
+
+    fn advance(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+
+    fn error(&self, message: String, line: usize, column: usize) -> SyntaxError {
+        SyntaxError { message, line, column }
+    }
+
+    fn skip_whitespace_and_comments(&mut self) {
+        loop {
+            match self.peek() {
+                Some(c) if c.is_whitespace() => {
+                    self.advance();
+                }
+                // -- comment until the end of the line
+                Some('-') if self.peek_at(1) == Some('-') => {
+                    while let Some(c) = self.advance() {
+                        if c == '\n' {
+                            break;
+                        }
+                    }
+                }
+                _ => {
+                    return;
+                }
+            }
+        }
+    }
+
+    // Read until the closing quote, a doubled quote stands for the quote itself
+    fn read_quoted(&mut self, quote: char, line: usize, column: usize) -> Result<String, SyntaxError> {
+        self.advance(); // opening quote
+        let mut text = String::new();
+        loop {
+            match self.advance() {
+                Some(c) if c == quote => {
+                    if self.peek() == Some(quote) {
+                        self.advance();
+                        text.push(quote);
+                    } else {
+                        return Ok(text);
+                    }
+                }
+                Some(c) => text.push(c),
+                None => {
+                    let what = if quote == '\'' { "string literal" } else { "quoted identifier" };
+                    return Err(self.error(format!("unterminated {}", what), line, column));
+                }
+            }
+        }
+    }
+
+    fn read_number(&mut self) -> String {
+        let mut number = String::new();
+        while let Some(c) = self.peek() {
+            let is_decimal_point = c == '.' && !number.contains('.') && !number.contains('e');
+            if c.is_ascii_digit() || is_decimal_point {
+                number.push(c);
+                self.advance();
+            } else if
+                (c == 'e' || c == 'E') &&
+                !number.contains('e') &&
+                self.peek_at(1).is_some_and(|next| next.is_ascii_digit() || next == '-' || next == '+')
+            {
+                number.push('e');
+                self.advance();
+                if let Some(sign) = self.peek().filter(|sign| *sign == '-' || *sign == '+') {
+                    number.push(sign);
+                    self.advance();
+                }
+            } else {
+                break;
+            }
+        }
+        number
+    }
+
+    fn next_token(&mut self) -> Result<Token, SyntaxError> {
+        self.skip_whitespace_and_comments();
+        let (line, column) = (self.line, self.column);
+        let token = |kind| Token { kind, line, column };
+
+        let c = match self.peek() {
+            Some(c) => c,
+            None => {
+                return Ok(token(TokenKind::Eof));
+            }
+        };
+
+        if c.is_alphabetic() || c == '_' {
+            let mut word = String::new();
+            while let Some(c) = self.peek() {
+                if c.is_alphanumeric() || c == '_' {
+                    word.push(c);
+                    self.advance();
+                } else {
+                    break;
+                }
+            }
+            return Ok(token(TokenKind::Ident(word)));
+        }
+        if c.is_ascii_digit() {
+            return Ok(token(TokenKind::Number(self.read_number())));
+        }
+        if c == '\'' {
+            return Ok(token(TokenKind::String(self.read_quoted('\'', line, column)?)));
+        }
+        if c == '"' {
+            let ident = self.read_quoted('"', line, column)?;
+            if ident.is_empty() {
+                return Err(self.error("empty quoted identifier".to_string(), line, column));
+            }
+            return Ok(token(TokenKind::QuotedIdent(ident)));
+        }
+
+        let next = self.peek_at(1);
+        let (kind, len) = match (c, next) {
+            ('<', Some('=')) => (TokenKind::LtEq, 2),
+            ('<', Some('>')) => (TokenKind::NotEq, 2),
+            ('>', Some('=')) => (TokenKind::GtEq, 2),
+            ('!', Some('=')) => (TokenKind::NotEq, 2),
+            ('|', Some('|')) => (TokenKind::Concat, 2),
+            (',', _) => (TokenKind::Comma, 1),
+            ('.', _) => (TokenKind::Dot, 1),
+            ('(', _) => (TokenKind::LeftParen, 1),
+            (')', _) => (TokenKind::RightParen, 1),
+            ('*', _) => (TokenKind::Star, 1),
+            (';', _) => (TokenKind::Semicolon, 1),
+            ('=', _) => (TokenKind::Eq, 1),
+            ('<', _) => (TokenKind::Lt, 1),
+            ('>', _) => (TokenKind::Gt, 1),
+            ('+', _) => (TokenKind::Plus, 1),
+            ('-', _) => (TokenKind::Minus, 1),
+            ('/', _) => (TokenKind::Slash, 1),
+            ('%', _) => (TokenKind::Percent, 1),
+            _ => {
+                return Err(self.error(format!("unexpected character '{}'", c), line, column));
+            }
+        };
+        for _ in 0..len {
+            self.advance();
+        }
+        Ok(token(kind))
+    }
+}
+
+// Split a query into tokens, the last token is always Eof
+pub fn tokenize(input: &str) -> Result<MyVec<Token>, SyntaxError> {
+    let mut lexer = Lexer {
+        chars: input.chars().collect(),
+        pos: 0,
+        line: 1,
+        column: 1,
+    };
+    let mut tokens = MyVec::new();
+    loop {
+        let token = lexer.next_token()?;
+        let is_eof = token.kind == TokenKind::Eof;
+        tokens.push(token);
+        if is_eof {
+            return Ok(tokens);
+        }
+    }
+}
@@ -0,0 +1,199 @@
+use std::collections::HashMap;
+use std::fs::{ File, OpenOptions };
+use std::os::unix::io::AsRawFd;
+use std::sync::{ Arc, Mutex, OnceLock };
+use std::thread;
+use std::time::{ Duration, Instant };
+use parking_lot::{ RawRwLock, RwLock };
+use parking_lot::lock_api::{ ArcRwLockReadGuard, ArcRwLockWriteGuard };
+use crate::structs::{ InsertSource, OnDelete, SelectItem, Statement };
+use crate::constraint::{ references_from, references_to };
+use crate::{ MyVec, Schema };
+
+// A statement that writes locks the table it changes for itself and shares
+// the tables it reads with other readers, until the statement ends. Every
+// connection task of the server goes through the registry below, other
+// server processes on the same database are kept out by an advisory lock
+// on the `{table}_lock` file. Waiting for a lock blocks the thread, so the
+// server runs statements on its blocking threads.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LockMode {
+    Shared,
+    Exclusive,
+}
+
+// Lock of every table in this process, by table directory
+fn table_lock(key: &str) -> Arc<RwLock<()>> {
+    static REGISTRY: OnceLock<Mutex<HashMap<String, Arc<RwLock<()>>>>> = OnceLock::new();
+    let mut locks = match REGISTRY.get_or_init(|| Mutex::new(HashMap::new())).lock() {
+        Ok(locks) => locks,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    locks.entry(key.to_string()).or_default().clone()
+}
+
+// Held only to be released on drop
+#[allow(dead_code)]
+enum Guard {
+    Shared(ArcRwLockReadGuard<RawRwLock, ()>),
+    Exclusive(ArcRwLockWriteGuard<RawRwLock, ()>),
+}
+
+// A held lock, released when dropped
+pub struct TableLock {
+    // Closing the file releases the advisory lock
+    _file: File,
+    _guard: Guard,
+}
+
+// The tables a statement writes and, for a write, the tables it reads,
+// including the tables its foreign keys check or change
+pub fn statement_locks(statement: &Statement, schema: &Schema) -> MyVec<(String, LockMode)> {
+    let mut read = MyVec::new();
+    let mut written = MyVec::new();
+    let target = match statement {
+        // Readers see a snapshot and never wait, see crate::transaction
+        Statement::Select(_) => None,
+        Statement::Insert(query) => {
+            if let InsertSource::Select(select) = &query.source {
+                select.push_tables(&mut read);
+            }
+            for item in query.returning.iter() {
+                if let SelectItem::Expr(expr, _) = item {
+                    expr.push_tables(&mut read);
+                }
+            }
+            push_parents(schema, &query.table, &mut read);
+            Some(&query.table)
+        }
+        Statement::Update(query) => {
+            if let Some(condition) = &query.condition {
+                condition.push_tables(&mut read);
+            }
+            push_parents(schema, &query.table, &mut read);
+            if let Ok(references) = references_to(schema, &query.table) {
+                read.extend(references.iter().map(|reference| reference.table.clone()));
+            }
+            Some(&query.table)
+        }
+        Statement::Delete(query) => {
+            query.condition.push_tables(&mut read);
+            push_children(schema, &query.table, &mut read, &mut written);
+            Some(&query.table)
+        }
+        Statement::Begin | Statement::Commit | Statement::Rollback | Statement::Vacuum(_) => None,
+    };
+    if let Some(table) = target {
+        written.push(table.clone());
+    }
+
+    let mut locks: MyVec<(String, LockMode)> = read
+        .iter()
+        .map(|table| (table.clone(), LockMode::Shared))
+        .collect();
+    locks.extend(written.iter().map(|table| (table.clone(), LockMode::Exclusive)));
+    locks
+}
+
+// Tables the rows of a table refer to
+fn push_parents(schema: &Schema, table: &str, read: &mut MyVec<String>) {
+    if let Ok(references) = references_from(schema, table) {
+        read.extend(references.iter().map(|reference| reference.parent.clone()));
+    }
+}
+
+// Tables whose rows refer to a table a DELETE removes rows from, they are
+// checked or changed along with it
+fn push_children(schema: &Schema, table: &str, read: &mut MyVec<String>, written: &mut MyVec<String>) {
+    let references = match references_to(schema, table) {
+        Ok(references) => references,
+        Err(_) => {
+            return;
+        }
+    };
+    for reference in references.iter() {
+        if reference.on_delete == OnDelete::Restrict {
+            read.push(reference.table.clone());
+            continue;
+        }
+        if written.iter().any(|written| *written == reference.table) {
+            continue;
+        }
+        written.push(reference.table.clone());
+        if reference.on_delete == OnDelete::Cascade {
+            push_children(schema, &reference.table, read, written);
+        }
+    }
+}
+
+// Lock the tables or time out. Tables are locked in name order, so two
+// statements never wait for each other.
+pub fn lock_tables(schema: &Schema, requests: &MyVec<(String, LockMode)>) -> Result<MyVec<TableLock>, String> {
+    let mut wanted: MyVec<(String, LockMode)> = MyVec::new();
+    for (table, mode) in requests.iter() {
+        if !schema.structure.contains_key(table) {
+            continue;
+        }
+        match wanted.iter_mut().find(|(name, _)| name == table) {
+            Some(entry) => {
+                if *mode == LockMode::Exclusive {
+                    entry.1 = LockMode::Exclusive;
+                }
+            }
+            None => wanted.push((table.clone(), *mode)),
+        }
+    }
+    wanted.as_mut_slice().sort_by(|a, b| a.0.cmp(&b.0));
+
+    let deadline = Instant::now() + Duration::from_millis(schema.lock_timeout_ms);
+    let mut held = MyVec::new();
+    for (table, mode) in wanted.iter() {
+        held.push(lock_table(schema, table, *mode, deadline)?);
+    }
+    Ok(held)
+}
+
+fn lock_table(schema: &Schema, table: &str, mode: LockMode, deadline: Instant) -> Result<TableLock, String> {
+    let timed_out = || {
+        format!("Timed out after {} ms waiting for a lock on table {}", schema.lock_timeout_ms, table)
+    };
+    let key = format!("{}/{}", schema.name, table);
+    let lock_path = format!("{}/{}_lock", key, table);
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)
+        .map_err(|e| format!("Failed to open {}: {}", lock_path, e))?;
+
+    let lock = table_lock(&key);
+    let wait = deadline.saturating_duration_since(Instant::now());
+    let guard = match mode {
+        LockMode::Shared => lock.try_read_arc_for(wait).map(Guard::Shared),
+        LockMode::Exclusive => lock.try_write_arc_for(wait).map(Guard::Exclusive),
+    };
+    let guard = match guard {
+        Some(guard) => guard,
+        None => {
+            return Err(timed_out());
+        }
+    };
+
+    // Another process holds the file for as long as its statement runs,
+    // flock has no timeout of its own
+    let operation = match mode {
+        LockMode::Shared => libc::LOCK_SH,
+        LockMode::Exclusive => libc::LOCK_EX,
+    };
+    loop {
+        // SAFETY: flock only takes the descriptor of the file opened above
+        if unsafe { libc::flock(file.as_raw_fd(), operation | libc::LOCK_NB) } == 0 {
+            return Ok(TableLock { _file: file, _guard: guard });
+        }
+        if Instant::now() >= deadline {
+            return Err(timed_out());
+        }
+        thread::sleep(Duration::from_millis(5));
+    }
+}
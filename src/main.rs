@@ -1,48 +1,60 @@
 mod querry_parser;
+mod lexer;
+mod executor;
 mod vector;
 mod hash_map;
 mod db_api;
 mod structs;
 mod utils;
+mod value;
+mod aggregate;
+mod functions;
+mod transaction;
+mod wal;
+mod lock;
+mod constraint;
 
 #[cfg(test)]
 mod tests;
 
+use std::sync::Arc;
 use tokio::net::TcpListener;
 #[allow(unused_imports)]
 use tokio::io::{ AsyncReadExt, AsyncWriteExt };
-use structs::{ Schema, Condition, DbResponse };
+use structs::{ Schema, DbResponse };
 use db_api::{ execute_query, init_db, clear_csv_files };
+use transaction::Session;
 use vector::MyVec;
 use hash_map::MyHashMap;
-use utils::read_schema;
+use utils::{ encode_result_row, read_schema };
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
-    let schema: Schema;
-    match read_schema("src/schema.json") {
-        Ok(output) => {
-            schema = output;
-        }
+    let schema: Schema = match read_schema("src/schema.json") {
+        Ok(output) => output,
         Err(e) => {
             println!("Failed to read schema: {}", e);
             std::process::exit(1);
         }
-    }
+    };
 
     init_db(&schema);
 
     let listener = TcpListener::bind("0.0.0.0:1337").await?;
 
+    let schema = Arc::new(schema);
+
     loop {
         let (mut socket, _) = listener.accept().await?;
-        let schema = schema.clone();
+        let schema = Arc::clone(&schema);
 
         tokio::spawn(async move {
             let mut buffer = vec![0; 1024];
+            // An open transaction is rolled back when the connection closes
+            let mut session = Session::new();
             loop {
                 let received_data = match socket.read(&mut buffer).await {
-                    Ok(inp) if inp == 0 => {
+                    Ok(0) => {
                         return;
                     }
                     Ok(inp) => { inp }
@@ -59,10 +71,31 @@ async fn main() -> std::io::Result<()> {
                     }
                 };
 
-                if received_data.trim() == "CLEAR DB" {
-                    clear_csv_files(&schema);
-                } else {
-                    match execute_query(received_data, &schema) {
+                // Statements wait for table locks and do file IO, they run
+                // on the blocking threads so other connections keep going
+                let statement_schema = Arc::clone(&schema);
+                let task = tokio::task::spawn_blocking(move || {
+                    let response = if received_data.trim() == "CLEAR DB" {
+                        clear_csv_files(&statement_schema);
+                        None
+                    } else {
+                        Some(execute_query(received_data, &statement_schema, &mut session))
+                    };
+                    (response, session)
+                });
+                let response = match task.await {
+                    Ok((response, returned)) => {
+                        session = returned;
+                        response
+                    }
+                    Err(e) => {
+                        eprintln!("Statement task failed: {}", e);
+                        return;
+                    }
+                };
+
+                if let Some(response) = response {
+                    match response {
                         DbResponse::Success(None) => {
                             socket.write_all("SUCCES\n".as_bytes()).await.unwrap();
                             socket.write_all("END\n".as_bytes()).await.unwrap();
@@ -74,11 +107,7 @@ async fn main() -> std::io::Result<()> {
                         DbResponse::Success(Some(matrix)) => {
                             socket.write_all("SUCCES\n".as_bytes()).await.unwrap();
                             for row in matrix.iter() {
-                                let row_str = row
-                                    .iter()
-                                    .map(|x| x.to_string())
-                                    .collect::<Vec<String>>()
-                                    .join(" ");
+                                let row_str = encode_result_row(row);
                                 socket
                                     .write_all(format!("{}\n", row_str).as_bytes()).await
                                     .unwrap();
@@ -1,308 +1,783 @@
-use crate::{ Schema, Condition, DbResponse };
-use crate::{ MyVec, MyHashMap };
-use crate::db_api::{ /*lock_table, unlock_table, is_locked,*/ increment_pk_sequence };
-use crate::utils::{ cartesian_product, read_all_table_data, find_not_full_csv };
-use std::fs::OpenOptions;
-use std::io::{ BufRead, Write, BufReader };
-use std::sync::{ Mutex, Arc };
-
-//Execute Functions
-fn execute_insert(table: &str, values_list: MyVec<&str>, schema: &Schema) -> DbResponse {
-    for value in values_list.iter() {
-        let cleaned_value = value
-            .replace("'", "")
-            .replace("(", "")
-            .replace(")", "")
-            .replace(" ", "");
-
-        let not_full_csv_index = match find_not_full_csv(schema, table) {
-            Ok(i) => i,
-            Err(e) => {
-                return DbResponse::Error(e);
-            }
-        };
-        let path = format!("{}/{}/{}.csv", schema.name, table, not_full_csv_index);
-
-        let not_full_csv_mutex = Arc::new(
-            Mutex::new(
-                OpenOptions::new()
-                    .write(true)
-                    .append(true)
-                    .open(&path)
-                    .expect("failed to open CSV file for writing")
-            )
-        );
-        let mut not_full_csv = match not_full_csv_mutex.lock() {
-            Ok(m) => m,
-            Err(_) => {
-                return DbResponse::Error("Table is currently locked".to_string());
-            }
-        };
-        let id = increment_pk_sequence(schema.name.as_str(), table);
-        writeln!(not_full_csv, "{}", format!("{},{}", id, cleaned_value)).expect(
-            "failed to write data to CSV"
-        );
+use crate::MyVec;
+use crate::lexer::{ tokenize, Token, TokenKind };
+use crate::structs::{
+    AggregateFunction,
+    BinaryOp,
+    ScalarFunction,
+    ColumnRef,
+    CompareOp,
+    DeleteQuery,
+    Expr,
+    FromItem,
+    InsertQuery,
+    InsertSource,
+    Join,
+    JoinKind,
+    SelectItem,
+    SelectQuery,
+    OrderItem,
+    TableRef,
+    Statement,
+    SyntaxError,
+    UpdateQuery,
+};
+use crate::value::Value;
+
+// Words that cannot be used as bare identifiers
+const RESERVED_WORDS: [&str; 43] = [
+    "SELECT",
+    "FROM",
+    "WHERE",
+    "AND",
+    "OR",
+    "NOT",
+    "INSERT",
+    "INTO",
+    "VALUES",
+    "UPDATE",
+    "SET",
+    "DELETE",
+    "LIKE",
+    "NULL",
+    "TRUE",
+    "FALSE",
+    "JOIN",
+    "INNER",
+    "LEFT",
+    "RIGHT",
+    "OUTER",
+    "CROSS",
+    "ON",
+    "AS",
+    "ORDER",
+    "BY",
+    "ASC",
+    "DESC",
+    "LIMIT",
+    "OFFSET",
+    "GROUP",
+    "HAVING",
+    "DISTINCT",
+    "CASE",
+    "WHEN",
+    "THEN",
+    "ELSE",
+    "END",
+    "IN",
+    "EXISTS",
+    "IS",
+    "DEFAULT",
+    "RETURNING",
+];
+
+pub fn parse_query(query: &str) -> Result<Statement, SyntaxError> {
+    let mut parser = Parser {
+        tokens: tokenize(query)?,
+        pos: 0,
+    };
+    let statement = parser.parse_statement()?;
+    parser.eat(&TokenKind::Semicolon);
+    if parser.peek().kind != TokenKind::Eof {
+        return Err(parser.unexpected("end of query"));
     }
-    return DbResponse::Success(None);
+    Ok(statement)
 }
 
-fn execute_delete(
-    table: &str,
-    parsed_conditions: MyVec<MyVec<Condition>>,
-    schema: &Schema
-) -> DbResponse {
-    if let Some(head) = schema.structure.get(table) {
-        let mut file_index = 0;
+fn describe(kind: &TokenKind) -> String {
+    match kind {
+        TokenKind::Ident(word) => format!("'{}'", word),
+        TokenKind::QuotedIdent(ident) => format!("\"{}\"", ident),
+        TokenKind::String(text) => format!("string '{}'", text),
+        TokenKind::Number(number) => format!("number {}", number),
+        TokenKind::Comma => "','".to_string(),
+        TokenKind::Dot => "'.'".to_string(),
+        TokenKind::LeftParen => "'('".to_string(),
+        TokenKind::RightParen => "')'".to_string(),
+        TokenKind::Star => "'*'".to_string(),
+        TokenKind::Semicolon => "';'".to_string(),
+        TokenKind::Eq => "'='".to_string(),
+        TokenKind::NotEq => "'<>'".to_string(),
+        TokenKind::Lt => "'<'".to_string(),
+        TokenKind::LtEq => "'<='".to_string(),
+        TokenKind::Gt => "'>'".to_string(),
+        TokenKind::GtEq => "'>='".to_string(),
+        TokenKind::Plus => "'+'".to_string(),
+        TokenKind::Minus => "'-'".to_string(),
+        TokenKind::Slash => "'/'".to_string(),
+        TokenKind::Percent => "'%'".to_string(),
+        TokenKind::Concat => "'||'".to_string(),
+        TokenKind::Eof => "end of query".to_string(),
+    }
+}
 
-        loop {
-            file_index += 1;
-            let mut remaining_lines: MyVec<String> = MyVec::new();
-            remaining_lines.push(head.join(",")); // Добавляем заголовок в оставшиеся строки
+struct Parser {
+    tokens: MyVec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.pos]
+    }
 
-            let path = format!("{}/{}/{}.csv", schema.name, table, file_index);
-            let file_result = OpenOptions::new().read(true).open(&path);
+    // A token further ahead, the query always ends with Eof
+    fn peek_at(&self, offset: usize) -> &Token {
+        let last = self.tokens.len() - 1;
+        &self.tokens[(self.pos + offset).min(last)]
+    }
 
-            // Если файл не найден, выходим из цикла
-            let file = match file_result {
-                Ok(file) => file,
-                Err(_) => {
-                    break;
-                }
-            };
+    fn advance(&mut self) -> Token {
+        let token = self.tokens[self.pos].clone();
+        if token.kind != TokenKind::Eof {
+            self.pos += 1;
+        }
+        token
+    }
+
+    fn unexpected(&self, expected: &str) -> SyntaxError {
+        let token = self.peek();
+        SyntaxError {
+            message: format!("expected {}, found {}", expected, describe(&token.kind)),
+            line: token.line,
+            column: token.column,
+        }
+    }
 
-            // Сохраняем результат Mutex в переменную
-            let file_mutex = Mutex::new(file);
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(&self.peek().kind, TokenKind::Ident(word) if word.eq_ignore_ascii_case(keyword))
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        if self.is_keyword(keyword) {
+            self.advance();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), SyntaxError> {
+        if self.eat_keyword(keyword) { Ok(()) } else { Err(self.unexpected(keyword)) }
+    }
+
+    fn eat(&mut self, kind: &TokenKind) -> bool {
+        if &self.peek().kind == kind {
+            self.advance();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, kind: TokenKind) -> Result<(), SyntaxError> {
+        if self.eat(&kind) { Ok(()) } else { Err(self.unexpected(&describe(&kind))) }
+    }
+
+    fn parse_identifier(&mut self) -> Result<String, SyntaxError> {
+        match &self.peek().kind {
+            TokenKind::Ident(word) if !is_reserved(word) => {
+                let word = word.clone();
+                self.advance();
+                Ok(word)
+            }
+            TokenKind::QuotedIdent(ident) => {
+                let ident = ident.clone();
+                self.advance();
+                Ok(ident)
+            }
+            _ => Err(self.unexpected("identifier")),
+        }
+    }
 
-            // Блокируем файл для чтения
-            let table_file = match file_mutex.lock() {
-                Ok(m) => m,
-                Err(_) => {
-                    return DbResponse::Error("Table is currently locked".to_string());
+    fn parse_column_ref(&mut self) -> Result<ColumnRef, SyntaxError> {
+        let first = self.parse_identifier()?;
+        if self.eat(&TokenKind::Dot) {
+            Ok(ColumnRef {
+                table: Some(first),
+                column: self.parse_identifier()?,
+            })
+        } else {
+            Ok(ColumnRef {
+                table: None,
+                column: first,
+            })
+        }
+    }
+
+    fn parse_literal(&mut self) -> Result<Value, SyntaxError> {
+        let negative = self.eat(&TokenKind::Minus);
+        let token = self.peek().clone();
+        let value = match &token.kind {
+            TokenKind::Number(number) => {
+                let number = if negative { format!("-{}", number) } else { number.clone() };
+                match number.parse::<i64>() {
+                    Ok(integer) => Value::Integer(integer),
+                    Err(_) =>
+                        match number.parse::<f64>() {
+                            Ok(real) => Value::Real(real),
+                            Err(_) => {
+                                return Err(SyntaxError {
+                                    message: format!("invalid number {}", number),
+                                    line: token.line,
+                                    column: token.column,
+                                });
+                            }
+                        }
                 }
+            }
+            _ if negative => {
+                return Err(self.unexpected("number"));
+            }
+            TokenKind::String(text) => Value::Text(text.clone()),
+            TokenKind::Ident(word) if word.eq_ignore_ascii_case("NULL") => Value::Null,
+            TokenKind::Ident(word) if word.eq_ignore_ascii_case("TRUE") => Value::Boolean(true),
+            TokenKind::Ident(word) if word.eq_ignore_ascii_case("FALSE") => Value::Boolean(false),
+            _ => {
+                return Err(self.unexpected("literal value"));
+            }
+        };
+        self.advance();
+        Ok(value)
+    }
+
+    fn parse_statement(&mut self) -> Result<Statement, SyntaxError> {
+        if self.eat_keyword("SELECT") {
+            Ok(Statement::Select(self.parse_select()?))
+        } else if self.eat_keyword("INSERT") {
+            Ok(Statement::Insert(self.parse_insert()?))
+        } else if self.eat_keyword("UPDATE") {
+            Ok(Statement::Update(self.parse_update()?))
+        } else if self.eat_keyword("DELETE") {
+            Ok(Statement::Delete(self.parse_delete()?))
+        } else if self.eat_keyword("BEGIN") {
+            self.eat_keyword("TRANSACTION");
+            Ok(Statement::Begin)
+        } else if self.eat_keyword("COMMIT") {
+            Ok(Statement::Commit)
+        } else if self.eat_keyword("ROLLBACK") {
+            Ok(Statement::Rollback)
+        } else if self.eat_keyword("VACUUM") {
+            let table = match self.peek().kind {
+                TokenKind::Ident(_) => Some(self.parse_identifier()?),
+                _ => None,
             };
+            Ok(Statement::Vacuum(table))
+        } else {
+            Err(self.unexpected("SELECT, INSERT, UPDATE, DELETE, BEGIN, COMMIT, ROLLBACK or VACUUM"))
+        }
+    }
 
-            // Передаем файл в BufReader, используя deref() для доступа к файлу
-            let reader = BufReader::new(&*table_file);
-            let mut lines = reader.lines();
-            lines.next(); // Пропускаем заголовок
+    // SELECT t.a, u.b FROM t [LEFT] JOIN u ON ..., v [WHERE ...]
+    fn parse_select(&mut self) -> Result<SelectQuery, SyntaxError> {
+        let distinct = self.eat_keyword("DISTINCT");
+        let mut columns = MyVec::new();
+        loop {
+            columns.push(self.parse_select_item()?);
+            if !self.eat(&TokenKind::Comma) {
+                break;
+            }
+        }
 
-            // Обрабатываем каждую строку
-            for line in lines {
-                let line = line.unwrap();
-                let line: MyVec<&str> = line.split(",").collect();
-                let mut data_for_condition: MyHashMap<String, String> = MyHashMap::new();
+        self.expect_keyword("FROM")?;
+        let mut from = MyVec::new();
+        loop {
+            from.push(self.parse_from_item()?);
+            if !self.eat(&TokenKind::Comma) {
+                break;
+            }
+        }
 
-                for (field, value) in head.iter().zip(line.iter()) {
-                    data_for_condition.insert(format!("{}.{}", table, field), value.to_string());
-                }
+        let condition = if self.eat_keyword("WHERE") { Some(self.parse_expr()?) } else { None };
 
-                // Если строка не подлежит удалению, добавляем её в оставшиеся строки
-                if !execute_conditions(&parsed_conditions, &data_for_condition) {
-                    remaining_lines.push(line.join(","));
+        let mut group_by = MyVec::new();
+        if self.eat_keyword("GROUP") {
+            self.expect_keyword("BY")?;
+            loop {
+                group_by.push(self.parse_expr()?);
+                if !self.eat(&TokenKind::Comma) {
+                    break;
                 }
             }
-
-            // Открываем файл для записи, очищаем его
-            let mut table_file = match OpenOptions::new().write(true).truncate(true).open(&path) {
-                Ok(file) => file,
-                Err(_) => {
-                    return DbResponse::Error("Failed to open file for writing".to_string());
+        }
+        let having = if self.eat_keyword("HAVING") { Some(self.parse_expr()?) } else { None };
+
+        let mut order_by = MyVec::new();
+        if self.eat_keyword("ORDER") {
+            self.expect_keyword("BY")?;
+            loop {
+                let expr = self.parse_expr()?;
+                let descending = if self.eat_keyword("DESC") {
+                    true
+                } else {
+                    self.eat_keyword("ASC");
+                    false
+                };
+                order_by.push(OrderItem { expr, descending });
+                if !self.eat(&TokenKind::Comma) {
+                    break;
                 }
-            };
+            }
+        }
+        let limit = if self.eat_keyword("LIMIT") { Some(self.parse_count()?) } else { None };
+        let offset = if self.eat_keyword("OFFSET") { Some(self.parse_count()?) } else { None };
+
+        Ok(SelectQuery {
+            distinct,
+            columns,
+            from,
+            condition,
+            group_by,
+            having,
+            order_by,
+            limit,
+            offset,
+        })
+    }
 
-            // Записываем оставшиеся строки обратно в файл
-            for line in remaining_lines.iter() {
-                writeln!(table_file, "{}", line).expect("Failed to write line");
+    // Non-negative integer of LIMIT and OFFSET
+    fn parse_count(&mut self) -> Result<usize, SyntaxError> {
+        let count = match &self.peek().kind {
+            TokenKind::Number(number) => number.parse::<usize>().ok(),
+            _ => None,
+        };
+        match count {
+            Some(count) => {
+                self.advance();
+                Ok(count)
             }
+            None => Err(self.unexpected("non-negative integer")),
         }
+    }
 
-        DbResponse::Success(None)
-    } else {
-        DbResponse::Error("No such table in DB".to_string())
+    fn parse_select_item(&mut self) -> Result<SelectItem, SyntaxError> {
+        if self.eat(&TokenKind::Star) {
+            return Ok(SelectItem::Wildcard);
+        }
+        // table.*
+        let is_table_wildcard =
+            matches!(self.peek().kind, TokenKind::Ident(_) | TokenKind::QuotedIdent(_)) &&
+            self.peek_at(1).kind == TokenKind::Dot &&
+            self.peek_at(2).kind == TokenKind::Star;
+        if is_table_wildcard {
+            let table = self.parse_identifier()?;
+            self.advance();
+            self.advance();
+            return Ok(SelectItem::TableWildcard(table));
+        }
+        let expr = self.parse_expr()?;
+        let alias = self.parse_alias()?;
+        Ok(SelectItem::Expr(expr, alias))
     }
-}
 
-fn execute_select(
-    tables: MyVec<&str>,
-    columns: MyVec<&str>,
-    conditions: Option<MyVec<MyVec<Condition>>>,
-    schema: &Schema
-) -> DbResponse {
-    let mut table_columns: MyHashMap<String, MyVec<String>> = MyHashMap::new();
+    // [[AS] alias]
+    fn parse_alias(&mut self) -> Result<Option<String>, SyntaxError> {
+        if self.eat_keyword("AS") {
+            return Ok(Some(self.parse_identifier()?));
+        }
+        match &self.peek().kind {
+            TokenKind::Ident(word) if !is_reserved(word) => Ok(Some(self.parse_identifier()?)),
+            TokenKind::QuotedIdent(_) => Ok(Some(self.parse_identifier()?)),
+            _ => Ok(None),
+        }
+    }
 
-    for table in tables.iter() {
-        table_columns.insert(table.to_string(), MyVec::new());
+    // table [[AS] alias]
+    fn parse_table_ref(&mut self) -> Result<TableRef, SyntaxError> {
+        let name = self.parse_identifier()?;
+        let alias = self.parse_alias()?;
+        Ok(TableRef { name, alias })
     }
 
-    for column in columns.iter() {
-        if let Some((table, column_name)) = column.split_once(".") {
-            if let Some(columns_vector) = table_columns.get_mut(&table.to_string()) {
-                columns_vector.push(column_name.to_string());
+    fn parse_from_item(&mut self) -> Result<FromItem, SyntaxError> {
+        let table = self.parse_table_ref()?;
+        let mut joins = MyVec::new();
+        loop {
+            let kind = if self.eat_keyword("CROSS") {
+                JoinKind::Cross
+            } else if self.eat_keyword("INNER") {
+                JoinKind::Inner
+            } else if self.eat_keyword("LEFT") {
+                self.eat_keyword("OUTER");
+                JoinKind::Left
+            } else if self.eat_keyword("RIGHT") {
+                self.eat_keyword("OUTER");
+                JoinKind::Right
+            } else if self.is_keyword("JOIN") {
+                JoinKind::Inner
+            } else {
+                break;
+            };
+            self.expect_keyword("JOIN")?;
+            let table = self.parse_table_ref()?;
+            let condition = if kind == JoinKind::Cross {
+                None
+            } else {
+                self.expect_keyword("ON")?;
+                Some(self.parse_expr()?)
+            };
+            joins.push(Join { kind, table, condition });
+        }
+        Ok(FromItem { table, joins })
+    }
+
+    // INSERT INTO t [(a, b)] { VALUES (v1, DEFAULT), (v3, v4) | SELECT ... } [RETURNING ...]
+    fn parse_insert(&mut self) -> Result<InsertQuery, SyntaxError> {
+        self.expect_keyword("INTO")?;
+        let table = self.parse_identifier()?;
+        let columns = if self.eat(&TokenKind::LeftParen) {
+            let mut columns = MyVec::new();
+            loop {
+                columns.push(self.parse_identifier()?);
+                if !self.eat(&TokenKind::Comma) {
+                    break;
+                }
+            }
+            self.expect(TokenKind::RightParen)?;
+            Some(columns)
+        } else {
+            None
+        };
+        let source = if self.eat_keyword("SELECT") {
+            InsertSource::Select(Box::new(self.parse_select()?))
+        } else {
+            self.expect_keyword("VALUES")?;
+            let mut rows = MyVec::new();
+            loop {
+                self.expect(TokenKind::LeftParen)?;
+                let mut values = MyVec::new();
+                loop {
+                    if self.eat_keyword("DEFAULT") {
+                        values.push(None);
+                    } else {
+                        values.push(Some(self.parse_literal()?));
+                    }
+                    if !self.eat(&TokenKind::Comma) {
+                        break;
+                    }
+                }
+                self.expect(TokenKind::RightParen)?;
+                rows.push(values);
+                if !self.eat(&TokenKind::Comma) {
+                    break;
+                }
+            }
+            InsertSource::Values(rows)
+        };
+
+        let mut returning = MyVec::new();
+        if self.eat_keyword("RETURNING") {
+            loop {
+                returning.push(self.parse_select_item()?);
+                if !self.eat(&TokenKind::Comma) {
+                    break;
+                }
             }
         }
+
+        Ok(InsertQuery { table, columns, source, returning })
     }
 
-    let tables: MyVec<String> = tables
-        .iter()
-        .map(|&s| s.to_string())
-        .collect();
-    let mut table_data: MyVec<MyVec<MyHashMap<String, String>>> = MyVec::new();
+    // UPDATE t SET a = 1, b = 'x' [WHERE ...]
+    fn parse_update(&mut self) -> Result<UpdateQuery, SyntaxError> {
+        let table = self.parse_identifier()?;
+        self.expect_keyword("SET")?;
 
-    for table in tables.iter() {
-        let data = match read_all_table_data(table, schema) {
-            Ok(d) => d,
-            Err(e) => {
-                return DbResponse::Error(e);
+        let mut assignments = MyVec::new();
+        loop {
+            let column = self.parse_column_ref()?;
+            self.expect(TokenKind::Eq)?;
+            assignments.push((column, self.parse_literal()?));
+            if !self.eat(&TokenKind::Comma) {
+                break;
             }
-        };
-        table_data.push(data);
+        }
+
+        let condition = if self.eat_keyword("WHERE") { Some(self.parse_expr()?) } else { None };
+        Ok(UpdateQuery {
+            table,
+            assignments,
+            condition,
+        })
     }
 
-    let mut joined_data = table_data[0].clone();
-    for i in 1..table_data.len() {
-        joined_data = cartesian_product(&joined_data, &table_data[i]);
+    // DELETE FROM t WHERE ...
+    fn parse_delete(&mut self) -> Result<DeleteQuery, SyntaxError> {
+        self.expect_keyword("FROM")?;
+        let table = self.parse_identifier()?;
+        self.expect_keyword("WHERE")?;
+        Ok(DeleteQuery {
+            table,
+            condition: self.parse_expr()?,
+        })
     }
 
-    let filtered_data = if let Some(conds) = conditions {
-        joined_data
-            .iter()
-            .cloned()
-            .filter(|row| execute_conditions(&conds, row))
-            .collect::<MyVec<_>>()
-    } else {
-        joined_data
-    };
+    // Boolean expressions, from the loosest binding operator to the tightest:
+    // OR, AND, NOT, comparison
+    fn parse_expr(&mut self) -> Result<Expr, SyntaxError> {
+        let mut left = self.parse_and()?;
+        while self.eat_keyword("OR") {
+            let right = self.parse_and()?;
+            left = Expr::Or(Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
 
-    let mut result_matrix = Vec::new();
+    fn parse_and(&mut self) -> Result<Expr, SyntaxError> {
+        let mut left = self.parse_not()?;
+        while self.eat_keyword("AND") {
+            let right = self.parse_not()?;
+            left = Expr::And(Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
 
-    for row in filtered_data.iter() {
-        let mut selected_row = Vec::new();
-        for (table, cols) in table_columns.iter() {
-            for col in cols.iter() {
-                let key = format!("{}.{}", table, col);
-                if let Some(value) = row.get(&key) {
-                    selected_row.push(value.clone());
-                }
-            }
+    fn parse_not(&mut self) -> Result<Expr, SyntaxError> {
+        if self.eat_keyword("NOT") {
+            Ok(Expr::Not(Box::new(self.parse_not()?)))
+        } else {
+            self.parse_comparison()
         }
-        result_matrix.push(selected_row);
     }
 
-    return DbResponse::Success(Some(result_matrix));
-}
+    fn parse_comparison(&mut self) -> Result<Expr, SyntaxError> {
+        let left = self.parse_additive()?;
 
-fn execute_conditions(
-    conditions: &MyVec<MyVec<Condition>>,
-    data: &MyHashMap<String, String>
-) -> bool {
-    for and_group in conditions.iter() {
-        let mut group_result = true;
-        for condition in and_group.iter() {
-            if let Some(data_value) = data.get(&condition.field) {
-                if data_value != &condition.value {
-                    group_result = false;
+        // IS [NOT] NULL
+        if self.eat_keyword("IS") {
+            let negated = self.eat_keyword("NOT");
+            self.expect_keyword("NULL")?;
+            return Ok(Expr::IsNull(Box::new(left), negated));
+        }
+
+        // [NOT] IN (SELECT ...) | [NOT] IN (value, ...)
+        let negated =
+            self.is_keyword("NOT") &&
+            matches!(
+                &self.peek_at(1).kind,
+                TokenKind::Ident(word) if word.eq_ignore_ascii_case("IN")
+            );
+        if negated {
+            self.advance();
+        }
+        if self.eat_keyword("IN") {
+            self.expect(TokenKind::LeftParen)?;
+            if self.eat_keyword("SELECT") {
+                let subquery = self.parse_select()?;
+                self.expect(TokenKind::RightParen)?;
+                return Ok(Expr::InSubquery(Box::new(left), Box::new(subquery), negated));
+            }
+            let mut list = MyVec::new();
+            loop {
+                list.push(self.parse_additive()?);
+                if !self.eat(&TokenKind::Comma) {
                     break;
                 }
-            } else {
-                group_result = false;
-                break;
             }
+            self.expect(TokenKind::RightParen)?;
+            return Ok(Expr::InList(Box::new(left), list, negated));
         }
-        if group_result {
-            return true;
-        }
+
+        let operator = match &self.peek().kind {
+            TokenKind::Eq => CompareOp::Eq,
+            TokenKind::NotEq => CompareOp::NotEq,
+            TokenKind::Lt => CompareOp::Lt,
+            TokenKind::LtEq => CompareOp::LtEq,
+            TokenKind::Gt => CompareOp::Gt,
+            TokenKind::GtEq => CompareOp::GtEq,
+            TokenKind::Ident(word) if word.eq_ignore_ascii_case("LIKE") => CompareOp::Like,
+            _ => {
+                return Ok(left);
+            }
+        };
+        self.advance();
+        let right = self.parse_additive()?;
+        Ok(Expr::Compare(Box::new(left), operator, Box::new(right)))
     }
-    return false;
-}
 
-//Parser functions
-pub fn parse_insert(input: String, schema: &Schema) -> DbResponse {
-    let parts: MyVec<&str> = input.split_whitespace().collect();
-    let table = parts[2];
+    fn parse_additive(&mut self) -> Result<Expr, SyntaxError> {
+        let mut left = self.parse_multiplicative()?;
+        loop {
+            let operator = match self.peek().kind {
+                TokenKind::Plus => BinaryOp::Add,
+                TokenKind::Minus => BinaryOp::Subtract,
+                TokenKind::Concat => BinaryOp::Concat,
+                _ => {
+                    return Ok(left);
+                }
+            };
+            self.advance();
+            let right = self.parse_multiplicative()?;
+            left = Expr::Binary(Box::new(left), operator, Box::new(right));
+        }
+    }
 
-    if let Some(values_index) = parts.iter().position(|&x| x == "VALUES") {
-        let values_part = &parts[values_index + 1..].join(" ");
-        let values = values_part.trim_start_matches('(').trim_end_matches(')').trim();
-        let values_list: MyVec<&str> = values.split("), (").collect();
-        return execute_insert(table, values_list, schema);
-    } else {
-        return DbResponse::Error("'VALUES' not found".to_string());
+    fn parse_multiplicative(&mut self) -> Result<Expr, SyntaxError> {
+        let mut left = self.parse_unary()?;
+        loop {
+            let operator = match self.peek().kind {
+                TokenKind::Star => BinaryOp::Multiply,
+                TokenKind::Slash => BinaryOp::Divide,
+                TokenKind::Percent => BinaryOp::Modulo,
+                _ => {
+                    return Ok(left);
+                }
+            };
+            self.advance();
+            let right = self.parse_unary()?;
+            left = Expr::Binary(Box::new(left), operator, Box::new(right));
+        }
     }
-}
 
-pub fn parse_delete(query: String, schema: &Schema) -> DbResponse {
-    let parts: MyVec<&str> = query.split(" ").collect();
-    let table = parts[2];
+    fn parse_unary(&mut self) -> Result<Expr, SyntaxError> {
+        if self.peek().kind == TokenKind::Minus {
+            // A negative number is read as a single literal
+            if matches!(self.peek_at(1).kind, TokenKind::Number(_)) {
+                return Ok(Expr::Literal(self.parse_literal()?));
+            }
+            self.advance();
+            return Ok(Expr::Negate(Box::new(self.parse_unary()?)));
+        }
+        self.parse_operand()
+    }
 
-    if let Some(parsed_conditions) = parse_where(&query) {
-        return execute_delete(table, parsed_conditions, schema);
-    } else {
-        return DbResponse::Error("No WHERE clause found".to_string());
+    // COUNT(*) | function([DISTINCT] expr)
+    fn parse_aggregate(&mut self, function: AggregateFunction) -> Result<Expr, SyntaxError> {
+        self.expect(TokenKind::LeftParen)?;
+        if function == AggregateFunction::Count && self.eat(&TokenKind::Star) {
+            self.expect(TokenKind::RightParen)?;
+            return Ok(Expr::Aggregate { function, argument: None, distinct: false });
+        }
+        let distinct = self.eat_keyword("DISTINCT");
+        let argument = self.parse_expr()?;
+        self.expect(TokenKind::RightParen)?;
+        Ok(Expr::Aggregate {
+            function,
+            argument: Some(Box::new(argument)),
+            distinct,
+        })
     }
-}
 
-pub fn parse_select(query: String, schema: &Schema) -> DbResponse {
-    let parts: MyVec<&str> = query.split(" ").collect();
-    let parsed_conditions = parse_where(&query);
-    let select_index = parts
-        .iter()
-        .position(|&x| x == "SELECT")
-        .expect("No SELECT found");
-    let from_index = parts
-        .iter()
-        .position(|&x| x == "FROM")
-        .expect("No FROM found");
-    let where_index: Option<usize> = parts.iter().position(|&x| x == "WHERE");
-
-    let columns_part = parts[select_index + 1..from_index].join(" ");
-    let columns: MyVec<&str> = columns_part
-        .split(',')
-        .map(|col| col.trim())
-        .collect();
-
-    let tables_part = if let Some(where_idx) = where_index {
-        parts[from_index + 1..where_idx].join(" ")
-    } else {
-        parts[from_index + 1..].join(" ")
-    };
-    let tables: MyVec<&str> = tables_part
-        .split(',')
-        .map(|table| table.trim())
-        .collect();
+    // name(argument, ...), the name is already consumed
+    fn parse_function(&mut self, name: &Token) -> Result<Expr, SyntaxError> {
+        let word = match &name.kind {
+            TokenKind::Ident(word) => word.as_str(),
+            _ => "",
+        };
+        if let Some(function) = aggregate_function(word) {
+            return self.parse_aggregate(function);
+        }
+        let (function, min_arguments, max_arguments) = match
+            word.to_ascii_uppercase().as_str()
+        {
+            "UPPER" => (ScalarFunction::Upper, 1, 1),
+            "LOWER" => (ScalarFunction::Lower, 1, 1),
+            "LENGTH" => (ScalarFunction::Length, 1, 1),
+            "SUBSTR" | "SUBSTRING" => (ScalarFunction::Substr, 2, 3),
+            "CONCAT" => (ScalarFunction::Concat, 1, usize::MAX),
+            "COALESCE" => (ScalarFunction::Coalesce, 1, usize::MAX),
+            _ => {
+                return Err(SyntaxError {
+                    message: format!("unknown function {}", word),
+                    line: name.line,
+                    column: name.column,
+                });
+            }
+        };
 
-    return execute_select(tables, columns, parsed_conditions, schema);
-}
+        self.expect(TokenKind::LeftParen)?;
+        let mut arguments = MyVec::new();
+        loop {
+            arguments.push(self.parse_expr()?);
+            if !self.eat(&TokenKind::Comma) {
+                break;
+            }
+        }
+        self.expect(TokenKind::RightParen)?;
+        if arguments.len() < min_arguments || arguments.len() > max_arguments {
+            let expected = if min_arguments == max_arguments {
+                format!("{}", min_arguments)
+            } else if max_arguments == usize::MAX {
+                format!("at least {}", min_arguments)
+            } else {
+                format!("{} to {}", min_arguments, max_arguments)
+            };
+            return Err(SyntaxError {
+                message: format!(
+                    "{} takes {} arguments, got {}",
+                    word.to_ascii_uppercase(),
+                    expected,
+                    arguments.len()
+                ),
+                line: name.line,
+                column: name.column,
+            });
+        }
+        Ok(Expr::Function(function, arguments))
+    }
 
-fn parse_where(querry: &String) -> Option<MyVec<MyVec<Condition>>> {
-    if let Some(where_index) = querry.find("WHERE") {
-        let where_clause = &querry[where_index + 6..];
-        let or_conditions: MyVec<&str> = where_clause.split(" OR ").collect();
-        let mut parsed_conditions = MyVec::new();
-        for or_condition in or_conditions.iter() {
-            let and_conditions: MyVec<&str> = or_condition.split(" AND ").collect();
-            let mut conditions = MyVec::new();
-            for condition in and_conditions.iter() {
-                if let Some(parsed_condition) = parse_condition(condition) {
-                    conditions.push(parsed_condition);
-                }
+    // CASE is already consumed
+    fn parse_case(&mut self) -> Result<Expr, SyntaxError> {
+        let operand = if self.is_keyword("WHEN") { None } else { Some(Box::new(self.parse_expr()?)) };
+        let mut branches = MyVec::new();
+        self.expect_keyword("WHEN")?;
+        loop {
+            let condition = self.parse_expr()?;
+            self.expect_keyword("THEN")?;
+            branches.push((condition, self.parse_expr()?));
+            if !self.eat_keyword("WHEN") {
+                break;
             }
-            parsed_conditions.push(conditions);
         }
-        return Some(parsed_conditions);
+        let otherwise = if self.eat_keyword("ELSE") { Some(Box::new(self.parse_expr()?)) } else { None };
+        self.expect_keyword("END")?;
+        Ok(Expr::Case { operand, branches, otherwise })
+    }
+
+    fn parse_operand(&mut self) -> Result<Expr, SyntaxError> {
+        if self.eat_keyword("EXISTS") {
+            self.expect(TokenKind::LeftParen)?;
+            self.expect_keyword("SELECT")?;
+            let subquery = self.parse_select()?;
+            self.expect(TokenKind::RightParen)?;
+            return Ok(Expr::Exists(Box::new(subquery)));
+        }
+        if self.eat(&TokenKind::LeftParen) {
+            if self.eat_keyword("SELECT") {
+                let subquery = self.parse_select()?;
+                self.expect(TokenKind::RightParen)?;
+                return Ok(Expr::Subquery(Box::new(subquery)));
+            }
+            let expr = self.parse_expr()?;
+            self.expect(TokenKind::RightParen)?;
+            return Ok(expr);
+        }
+        if self.eat_keyword("CASE") {
+            return self.parse_case();
+        }
+        let is_call = self.peek_at(1).kind == TokenKind::LeftParen;
+        match &self.peek().kind {
+            TokenKind::Ident(word) if is_call && !is_reserved(word) => {
+                let name = self.advance();
+                self.parse_function(&name)
+            }
+            TokenKind::Ident(word) if !is_reserved(word) => Ok(Expr::Column(self.parse_column_ref()?)),
+            TokenKind::QuotedIdent(_) => Ok(Expr::Column(self.parse_column_ref()?)),
+            _ => Ok(Expr::Literal(self.parse_literal()?)),
+        }
     }
-    None
 }
 
-fn parse_condition(condition: &str) -> Option<Condition> {
-    // Find the operator and split the condition
-    if let Some(pos) = condition.find("=") {
-        let field = condition[..pos].trim().to_string();
-        let value = condition[pos + 1..].trim().replace("'", "").to_string(); // Right operand (value)
-        return Some(Condition {
-            field,
-            value,
-        });
+fn aggregate_function(name: &str) -> Option<AggregateFunction> {
+    match name.to_ascii_uppercase().as_str() {
+        "COUNT" => Some(AggregateFunction::Count),
+        "SUM" => Some(AggregateFunction::Sum),
+        "AVG" => Some(AggregateFunction::Avg),
+        "MIN" => Some(AggregateFunction::Min),
+        "MAX" => Some(AggregateFunction::Max),
+        _ => None,
     }
+}
 
-    None
+fn is_reserved(word: &str) -> bool {
+    RESERVED_WORDS.iter().any(|reserved| reserved.eq_ignore_ascii_case(word))
 }
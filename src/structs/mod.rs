@@ -1,19 +1,530 @@
 use std::collections::HashMap;
+use std::fmt;
 use serde::{ Deserialize, Serialize };
+use crate::value::{ ColumnType, Value };
+use crate::{ MyVec, MyHashMap };
 
-pub struct Condition {
-    pub field: String,
-    pub value: String,
+// A row of one or several joined tables, keyed by `table.column`
+pub type Row = MyHashMap<String, Value>;
+
+// Transactions that created and deleted a stored row version, 0 for none.
+// See crate::transaction.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RowVersion {
+    pub xmin: u64,
+    pub xmax: u64,
+}
+
+// Where a row version is stored: the number of its block and its place
+// among the rows of the block. Only VACUUM moves stored versions, under an
+// exclusive lock of the table.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RowId {
+    pub block: usize,
+    pub line: usize,
+}
+
+impl RowId {
+    // Key of the version in a hash table of versions
+    pub fn hash_key(&self) -> String {
+        format!("{}:{}", self.block, self.line)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompareOp {
+    Eq,
+    NotEq,
+    Lt,
+    LtEq,
+    Gt,
+    GtEq,
+    Like,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+    // ||
+    Concat,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScalarFunction {
+    Upper,
+    Lower,
+    Length,
+    Substr,
+    Concat,
+    Coalesce,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AggregateFunction {
+    Count,
+    Sum,
+    Avg,
+    Min,
+    Max,
+}
+
+impl fmt::Display for AggregateFunction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            AggregateFunction::Count => "COUNT",
+            AggregateFunction::Sum => "SUM",
+            AggregateFunction::Avg => "AVG",
+            AggregateFunction::Min => "MIN",
+            AggregateFunction::Max => "MAX",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnRef {
+    pub table: Option<String>,
+    pub column: String,
+}
+
+impl ColumnRef {
+    // Key of the column in a row, rows are keyed by `table.column`
+    pub fn key(&self) -> String {
+        match &self.table {
+            Some(table) => format!("{}.{}", table, self.column),
+            None => self.column.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Column(ColumnRef),
+    Literal(Value),
+    Compare(Box<Expr>, CompareOp, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Binary(Box<Expr>, BinaryOp, Box<Expr>),
+    Negate(Box<Expr>),
+    Function(ScalarFunction, MyVec<Expr>),
+    // CASE [operand] WHEN .. THEN .. ELSE .. END, without an operand the
+    // WHEN expressions are conditions, with one they are compared to it
+    Case {
+        operand: Option<Box<Expr>>,
+        branches: MyVec<(Expr, Expr)>,
+        otherwise: Option<Box<Expr>>,
+    },
+    // expr IS [NOT] NULL
+    IsNull(Box<Expr>, bool),
+    // expr [NOT] IN (value, ...)
+    InList(Box<Expr>, MyVec<Expr>, bool),
+    // expr [NOT] IN (SELECT ...)
+    InSubquery(Box<Expr>, Box<SelectQuery>, bool),
+    Exists(Box<SelectQuery>),
+    // (SELECT ...) giving a single value
+    Subquery(Box<SelectQuery>),
+    // Column of an enclosing query, the number tells how many levels up
+    OuterColumn(ColumnRef, usize),
+    // COUNT(*) has no argument
+    Aggregate {
+        function: AggregateFunction,
+        argument: Option<Box<Expr>>,
+        distinct: bool,
+    },
+}
+
+impl Expr {
+    // Direct subexpressions, for walking the tree. Subqueries have their own
+    // scope and are not walked into.
+    pub fn children(&self) -> MyVec<&Expr> {
+        let mut children = MyVec::new();
+        match self {
+            Expr::Column(_) | Expr::Literal(_) | Expr::OuterColumn(..) => {}
+            Expr::Exists(_) | Expr::Subquery(_) => {}
+            Expr::InSubquery(left, _, _) => children.push(left.as_ref()),
+            Expr::InList(left, list, _) => {
+                children.push(left.as_ref());
+                for item in list.iter() {
+                    children.push(item);
+                }
+            }
+            Expr::Compare(left, _, right) |
+            Expr::And(left, right) |
+            Expr::Or(left, right) |
+            Expr::Binary(left, _, right) => {
+                children.push(left.as_ref());
+                children.push(right.as_ref());
+            }
+            Expr::Not(inner) | Expr::Negate(inner) | Expr::IsNull(inner, _) => {
+                children.push(inner.as_ref());
+            }
+            Expr::Function(_, arguments) => {
+                for argument in arguments.iter() {
+                    children.push(argument);
+                }
+            }
+            Expr::Case { operand, branches, otherwise } => {
+                if let Some(operand) = operand {
+                    children.push(operand.as_ref());
+                }
+                for (condition, result) in branches.iter() {
+                    children.push(condition);
+                    children.push(result);
+                }
+                if let Some(otherwise) = otherwise {
+                    children.push(otherwise.as_ref());
+                }
+            }
+            Expr::Aggregate { argument, .. } => {
+                if let Some(argument) = argument {
+                    children.push(argument.as_ref());
+                }
+            }
+        }
+        children
+    }
+
+    pub fn children_mut(&mut self) -> MyVec<&mut Expr> {
+        let mut children = MyVec::new();
+        match self {
+            Expr::Column(_) | Expr::Literal(_) | Expr::OuterColumn(..) => {}
+            Expr::Exists(_) | Expr::Subquery(_) => {}
+            Expr::InSubquery(left, _, _) => children.push(left.as_mut()),
+            Expr::InList(left, list, _) => {
+                children.push(left.as_mut());
+                for item in list.iter_mut() {
+                    children.push(item);
+                }
+            }
+            Expr::Compare(left, _, right) |
+            Expr::And(left, right) |
+            Expr::Or(left, right) |
+            Expr::Binary(left, _, right) => {
+                children.push(left.as_mut());
+                children.push(right.as_mut());
+            }
+            Expr::Not(inner) | Expr::Negate(inner) | Expr::IsNull(inner, _) => {
+                children.push(inner.as_mut());
+            }
+            Expr::Function(_, arguments) => {
+                for argument in arguments.iter_mut() {
+                    children.push(argument);
+                }
+            }
+            Expr::Case { operand, branches, otherwise } => {
+                if let Some(operand) = operand {
+                    children.push(operand.as_mut());
+                }
+                for (condition, result) in branches.iter_mut() {
+                    children.push(condition);
+                    children.push(result);
+                }
+                if let Some(otherwise) = otherwise {
+                    children.push(otherwise.as_mut());
+                }
+            }
+            Expr::Aggregate { argument, .. } => {
+                if let Some(argument) = argument {
+                    children.push(argument.as_mut());
+                }
+            }
+        }
+        children
+    }
+
+    // Add the tables read by the subqueries of the expression
+    pub fn push_tables(&self, tables: &mut MyVec<String>) {
+        match self {
+            Expr::Exists(subquery) | Expr::Subquery(subquery) | Expr::InSubquery(_, subquery, _) => {
+                subquery.push_tables(tables);
+            }
+            _ => {}
+        }
+        for child in self.children().iter() {
+            child.push_tables(tables);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JoinKind {
+    Inner,
+    Left,
+    Right,
+    Cross,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableRef {
+    pub name: String,
+    pub alias: Option<String>,
+}
+
+impl TableRef {
+    // Name the table is referred to by in the rest of the query
+    pub fn binding(&self) -> &str {
+        self.alias.as_deref().unwrap_or(&self.name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Join {
+    pub kind: JoinKind,
+    pub table: TableRef,
+    pub condition: Option<Expr>,
+}
+
+// One comma-separated entry of FROM: a table followed by explicit joins
+#[derive(Debug, Clone, PartialEq)]
+pub struct FromItem {
+    pub table: TableRef,
+    pub joins: MyVec<Join>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SelectItem {
+    // *
+    Wildcard,
+    // table.*
+    TableWildcard(String),
+    // expr [AS alias]
+    Expr(Expr, Option<String>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OrderItem {
+    pub expr: Expr,
+    pub descending: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelectQuery {
+    pub distinct: bool,
+    pub columns: MyVec<SelectItem>,
+    pub from: MyVec<FromItem>,
+    pub condition: Option<Expr>,
+    pub group_by: MyVec<Expr>,
+    pub having: Option<Expr>,
+    pub order_by: MyVec<OrderItem>,
+    pub limit: Option<usize>,
+    pub offset: Option<usize>,
+}
+
+impl SelectQuery {
+    // Add the tables the query reads, including those of its subqueries
+    pub fn push_tables(&self, tables: &mut MyVec<String>) {
+        for item in self.from.iter() {
+            tables.push(item.table.name.clone());
+            for join in item.joins.iter() {
+                tables.push(join.table.name.clone());
+            }
+        }
+        for expr in self.exprs().iter() {
+            expr.push_tables(tables);
+        }
+    }
+
+    // Every expression of the query itself, not of its subqueries
+    pub fn exprs(&self) -> MyVec<&Expr> {
+        let mut exprs = MyVec::new();
+        for item in self.columns.iter() {
+            if let SelectItem::Expr(expr, _) = item {
+                exprs.push(expr);
+            }
+        }
+        for item in self.from.iter() {
+            for join in item.joins.iter() {
+                if let Some(condition) = &join.condition {
+                    exprs.push(condition);
+                }
+            }
+        }
+        if let Some(condition) = &self.condition {
+            exprs.push(condition);
+        }
+        for group in self.group_by.iter() {
+            exprs.push(group);
+        }
+        if let Some(having) = &self.having {
+            exprs.push(having);
+        }
+        for item in self.order_by.iter() {
+            exprs.push(&item.expr);
+        }
+        exprs
+    }
+
+    pub fn exprs_mut(&mut self) -> MyVec<&mut Expr> {
+        let mut exprs = MyVec::new();
+        for item in self.columns.iter_mut() {
+            if let SelectItem::Expr(expr, _) = item {
+                exprs.push(expr);
+            }
+        }
+        for item in self.from.iter_mut() {
+            for join in item.joins.iter_mut() {
+                if let Some(condition) = join.condition.as_mut() {
+                    exprs.push(condition);
+                }
+            }
+        }
+        if let Some(condition) = self.condition.as_mut() {
+            exprs.push(condition);
+        }
+        for group in self.group_by.iter_mut() {
+            exprs.push(group);
+        }
+        if let Some(having) = self.having.as_mut() {
+            exprs.push(having);
+        }
+        for item in self.order_by.iter_mut() {
+            exprs.push(&mut item.expr);
+        }
+        exprs
+    }
+}
+
+pub struct InsertQuery {
+    pub table: String,
+    // Listed target columns, all columns after the primary key when None
+    pub columns: Option<MyVec<String>>,
+    pub source: InsertSource,
+    pub returning: MyVec<SelectItem>,
+}
+
+pub enum InsertSource {
+    // None stands for DEFAULT
+    Values(MyVec<MyVec<Option<Value>>>),
+    Select(Box<SelectQuery>),
+}
+
+pub struct UpdateQuery {
+    pub table: String,
+    pub assignments: MyVec<(ColumnRef, Value)>,
+    pub condition: Option<Expr>,
+}
+
+pub struct DeleteQuery {
+    pub table: String,
+    pub condition: Expr,
+}
+
+pub enum Statement {
+    Select(SelectQuery),
+    Insert(InsertQuery),
+    Update(UpdateQuery),
+    Delete(DeleteQuery),
+    Begin,
+    Commit,
+    Rollback,
+    // VACUUM [table], all tables when None
+    Vacuum(Option<String>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntaxError {
+    pub message: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Syntax error at line {}, column {}: {}", self.line, self.column, self.message)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Column {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub column_type: ColumnType,
+    #[serde(default = "default_nullable")]
+    pub nullable: bool,
+    // Value of the column when INSERT does not give one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default: Option<serde_json::Value>,
+}
+
+fn default_nullable() -> bool {
+    true
+}
+
+impl Column {
+    pub fn default_value(&self) -> Result<Value, String> {
+        let value = match &self.default {
+            None | Some(serde_json::Value::Null) => Value::Null,
+            Some(serde_json::Value::Bool(b)) => Value::Boolean(*b),
+            Some(serde_json::Value::Number(n)) => Value::Text(n.to_string()),
+            Some(serde_json::Value::String(s)) => Value::Text(s.clone()),
+            Some(other) => {
+                return Err(format!("Invalid default for column {}: {}", self.name, other));
+            }
+        };
+        value
+            .coerce(self.column_type)
+            .map_err(|e| format!("Invalid default for column {}: {}", self.name, e))
+    }
+}
+
+// Keys of a table, see crate::constraint
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct TableConstraints {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub primary_key: Option<Vec<String>>,
+    // Column sets no two rows may share
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unique: Vec<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub foreign_keys: Vec<ForeignKey>,
+}
+
+// Columns that hold the key of a row of another table
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ForeignKey {
+    pub columns: Vec<String>,
+    pub references: String,
+    // The primary key of the referenced table when not given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub referenced_columns: Option<Vec<String>>,
+    #[serde(default)]
+    pub on_delete: OnDelete,
+}
+
+// What happens to the referencing rows when a referenced row is deleted
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum OnDelete {
+    #[default]
+    #[serde(rename = "RESTRICT")]
+    Restrict,
+    #[serde(rename = "CASCADE")]
+    Cascade,
+    #[serde(rename = "SET NULL")]
+    SetNull,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Schema {
     pub name: String,
     pub tuples_limit: i32,
-    pub structure: HashMap<String, Vec<String>>,
+    // How long a statement waits for the tables it needs
+    #[serde(default = "default_lock_timeout")]
+    pub lock_timeout_ms: u64,
+    pub structure: HashMap<String, Vec<Column>>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub constraints: HashMap<String, TableConstraints>,
+}
+
+fn default_lock_timeout() -> u64 {
+    5000
 }
 
 pub enum DbResponse {
-    Success(Option<Vec<Vec<String>>>),
+    Success(Option<Vec<Vec<Value>>>),
     Error(String),
 }
@@ -0,0 +1,1209 @@
+use crate::db_api::{ execute_query, init_db };
+use crate::lock::{ lock_tables, LockMode };
+use crate::structs::{ DbResponse, OnDelete, Schema };
+use crate::transaction::Session;
+use crate::utils::{ encode_result_row, read_schema };
+use crate::wal::{ self, FileWrite };
+use crate::MyVec;
+use crate::value::{ ColumnType, Value };
+use std::sync::atomic::{ AtomicUsize, Ordering };
+
+static DB_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+// Every test gets its own database directory so tests can run in parallel
+fn test_schema() -> Schema {
+    let mut schema = read_schema("src/schema.json").expect("failed to read schema");
+    let id = DB_COUNTER.fetch_add(1, Ordering::SeqCst);
+    let dir = std::env::temp_dir().join(format!("dbms_test_{}_{}", std::process::id(), id));
+    let _ = std::fs::remove_dir_all(&dir);
+    schema.name = dir.to_string_lossy().to_string();
+    init_db(&schema);
+    schema
+}
+
+fn run(schema: &Schema, query: &str) -> Result<Vec<Vec<String>>, String> {
+    run_in(&mut Session::new(), schema, query)
+}
+
+fn run_in(session: &mut Session, schema: &Schema, query: &str) -> Result<Vec<Vec<String>>, String> {
+    match execute_query(query.to_string(), schema, session) {
+        DbResponse::Success(None) => Ok(Vec::new()),
+        DbResponse::Success(Some(matrix)) =>
+            Ok(
+                matrix
+                    .iter()
+                    .map(|row|
+                        row
+                            .iter()
+                            .map(|value| value.to_string())
+                            .collect()
+                    )
+                    .collect()
+            ),
+        DbResponse::Error(e) => Err(e),
+    }
+}
+
+fn rows(schema: &Schema, query: &str) -> Vec<Vec<String>> {
+    match run(schema, query) {
+        Ok(rows) => rows,
+        Err(e) => panic!("query `{}` failed: {}", query, e),
+    }
+}
+
+#[test]
+fn insert_stores_typed_values() {
+    let schema = test_schema();
+    insert_trading_data(&schema);
+    rows(&schema, "INSERT INTO pair VALUES (2, 1)");
+    rows(&schema, "INSERT INTO orders VALUES (1, 2, 10, 1.50, 'buy', NULL)");
+    let result = rows(&schema, "SELECT orders.price FROM orders WHERE orders.quantity = 10.0");
+    assert_eq!(result, vec![vec!["1.5".to_string()]]);
+}
+
+#[test]
+fn insert_rejects_values_of_wrong_type() {
+    let schema = test_schema();
+    let error = run(&schema, "INSERT INTO orders VALUES (1, 2, 'abc', 1.5, 'buy', NULL)").unwrap_err();
+    assert!(error.contains("orders.quantity"), "{}", error);
+    assert!(rows(&schema, "SELECT orders.order_id FROM orders").is_empty());
+}
+
+#[test]
+fn insert_rejects_null_in_not_null_column() {
+    let schema = test_schema();
+    let error = run(&schema, "INSERT INTO users VALUES ('alice', NULL)").unwrap_err();
+    assert!(error.contains("users.auth_key"), "{}", error);
+}
+
+#[test]
+fn timestamp_values_round_trip() {
+    let value = Value::parse("2024-02-29 13:05:09", ColumnType::Timestamp).unwrap();
+    assert_eq!(value.to_string(), "2024-02-29 13:05:09");
+    assert!(Value::parse("2023-02-29", ColumnType::Timestamp).is_err());
+    assert!(Value::parse("3000000000", ColumnType::Integer).is_err());
+}
+
+fn insert_orders(schema: &Schema) {
+    rows(schema, "INSERT INTO orders VALUES (1, 1, 5, 100.5, 'sell', NULL)");
+    rows(schema, "INSERT INTO orders VALUES (2, 1, 3, 99, 'buy', NULL)");
+    rows(schema, "INSERT INTO orders VALUES (3, 1, 8, 101, 'buy', '2024-05-01 10:00:00')");
+}
+
+#[test]
+fn where_supports_comparison_operators() {
+    let schema = test_schema();
+    insert_trading_data(&schema);
+    let select = "SELECT orders.order_id FROM orders WHERE ";
+    assert_eq!(rows(&schema, &format!("{}orders.price <= 100.5", select)).len(), 2);
+    assert_eq!(rows(&schema, &format!("{}orders.price > 99", select)).len(), 2);
+    assert_eq!(rows(&schema, &format!("{}orders.quantity < 5", select)), vec![vec!["2"]]);
+    assert_eq!(rows(&schema, &format!("{}orders.quantity >= 8", select)), vec![vec!["3"]]);
+    assert_eq!(rows(&schema, &format!("{}orders.order_type != 'buy'", select)), vec![vec!["1"]]);
+    assert_eq!(rows(&schema, &format!("{}orders.order_type <> 'sell'", select)).len(), 2);
+}
+
+#[test]
+fn where_supports_like_patterns() {
+    let schema = test_schema();
+    insert_trading_data(&schema);
+    let select = "SELECT orders.order_id FROM orders WHERE ";
+    assert_eq!(rows(&schema, &format!("{}orders.order_type LIKE 's%'", select)), vec![vec!["1"]]);
+    assert_eq!(rows(&schema, &format!("{}orders.order_type LIKE '_u_'", select)).len(), 2);
+    assert!(rows(&schema, &format!("{}orders.order_type LIKE 'b_'", select)).is_empty());
+}
+
+#[test]
+fn update_rewrites_matching_rows_in_place() {
+    let schema = test_schema();
+    insert_trading_data(&schema);
+    rows(&schema, "UPDATE orders SET closed = '2024-06-01 12:00:00', price = 98.5 WHERE orders.order_id = 2");
+    assert_eq!(
+        rows(&schema, "SELECT orders.price FROM orders WHERE orders.closed = '2024-06-01 12:00:00'"),
+        vec![vec!["98.5"]]
+    );
+    // The primary key is kept, so no new id is burnt from the sequence
+    rows(&schema, "INSERT INTO users VALUES ('dave', 'k4')");
+    rows(&schema, "INSERT INTO orders VALUES (4, 1, 1, 1, 'buy', NULL)");
+    assert_eq!(rows(&schema, "SELECT orders.order_id FROM orders WHERE orders.user_id = 4"), vec![vec!["4"]]);
+}
+
+#[test]
+fn update_validates_new_values() {
+    let schema = test_schema();
+    insert_trading_data(&schema);
+    assert!(run(&schema, "UPDATE orders SET price = 'abc' WHERE orders.order_id = 1").is_err());
+    assert!(run(&schema, "UPDATE orders SET quantity = NULL").is_err());
+    assert!(run(&schema, "UPDATE orders SET missing = 1").is_err());
+    assert_eq!(
+        run(&schema, "UPDATE orders SET order_id = 1").unwrap_err(),
+        "Column orders.order_id is generated and cannot be updated"
+    );
+    assert_eq!(rows(&schema, "SELECT orders.price FROM orders WHERE orders.order_id = 1"), vec![vec!["100.5"]]);
+}
+
+#[test]
+fn hash_map_lookup_of_missing_key_terminates() {
+    let mut map: crate::MyHashMap<String, i32> = crate::MyHashMap::new();
+    map.insert("a".to_string(), 1);
+    map.insert("b".to_string(), 2);
+    assert_eq!(map.get(&"c".to_string()), None);
+    assert_eq!(map.get(&"b".to_string()), Some(&2));
+}
+
+#[test]
+fn parser_accepts_free_form_queries() {
+    let schema = test_schema();
+    rows(&schema, "insert  into users\n  values ('O''Brien', 'key')  ;");
+    assert_eq!(
+        rows(&schema, "select \"users\".username\tFROM   users where users.auth_key='key'"),
+        vec![vec!["O'Brien"]]
+    );
+}
+
+#[test]
+fn syntax_errors_report_position() {
+    let schema = test_schema();
+    assert_eq!(
+        run(&schema, "SELECT users.username\nWHERE users").unwrap_err(),
+        "Syntax error at line 2, column 1: expected FROM, found 'WHERE'"
+    );
+    assert_eq!(
+        run(&schema, "SELECT users.username FROM users WHERE users.username = 'x").unwrap_err(),
+        "Syntax error at line 1, column 57: unterminated string literal"
+    );
+    assert!(run(&schema, "DROP TABLE users").unwrap_err().starts_with("Syntax error at line 1, column 1"));
+}
+
+#[test]
+fn truncated_queries_are_syntax_errors() {
+    let schema = test_schema();
+    let queries = [
+        "SELECT",
+        "SELECT a FROM users WHERE",
+        "DELETE FROM users WHERE",
+        "SELECT users.username FROM users ORDER BY",
+        "SELECT CASE",
+        "SELECT users.username FROM users WHERE users.user_id IN (",
+        "SELECT users.username FROM users WHERE users.user_id NOT",
+        "SELECT -",
+        "SELECT users",
+    ];
+    for query in queries {
+        let error = run(&schema, query).unwrap_err();
+        assert!(error.starts_with("Syntax error at line 1"), "{}: {}", query, error);
+    }
+}
+
+#[test]
+fn where_supports_nested_boolean_expressions() {
+    let schema = test_schema();
+    insert_trading_data(&schema);
+    let result = rows(
+        &schema,
+        "SELECT orders.order_id FROM orders \
+         WHERE (orders.quantity = 5 OR orders.quantity = 8) AND NOT orders.order_type = 'sell'"
+    );
+    assert_eq!(result, vec![vec!["3"]]);
+    let result = rows(
+        &schema,
+        "SELECT orders.order_id FROM orders \
+         WHERE NOT (orders.price > 100 AND (orders.order_type = 'buy' OR orders.quantity < 6))"
+    );
+    assert_eq!(result, vec![vec!["2"]]);
+    assert!(run(&schema, "SELECT orders.order_id FROM orders WHERE (orders.price > 1").is_err());
+}
+
+fn insert_trading_data(schema: &Schema) {
+    rows(schema, "INSERT INTO users VALUES ('alice', 'k1'), ('bob', 'k2'), ('carol', 'k3')");
+    rows(schema, "INSERT INTO lot VALUES ('BTC'), ('USD')");
+    rows(schema, "INSERT INTO pair VALUES (1, 2)");
+    insert_orders(schema);
+}
+
+// Rows and the values inside them in a fixed order
+fn sorted(mut result: Vec<Vec<String>>) -> Vec<Vec<String>> {
+    for row in result.iter_mut() {
+        row.sort();
+    }
+    result.sort();
+    result
+}
+
+#[test]
+fn where_joins_tables_on_column_equality() {
+    let schema = test_schema();
+    insert_trading_data(&schema);
+    let result = rows(
+        &schema,
+        "SELECT orders.order_id, users.username FROM orders, users \
+         WHERE orders.user_id = users.user_id AND orders.order_type = 'buy'"
+    );
+    assert_eq!(sorted(result), vec![vec!["2", "bob"], vec!["3", "carol"]]);
+}
+
+#[test]
+fn three_table_join_uses_every_join_condition() {
+    let schema = test_schema();
+    insert_trading_data(&schema);
+    let result = rows(
+        &schema,
+        "SELECT lot.name, orders.order_id FROM lot, orders, pair \
+         WHERE pair.pair_id = orders.pair_id AND lot.lot_id = pair.sale_lot_id"
+    );
+    assert_eq!(sorted(result), vec![vec!["1", "BTC"], vec!["2", "BTC"], vec!["3", "BTC"]]);
+    // Rows of a large table pairing only on the key, a cartesian product would be 10^6 rows
+    let users: Vec<String> = (4..=1000).map(|i| format!("('user{}', 'k')", i)).collect();
+    rows(&schema, &format!("INSERT INTO users VALUES {}", users.join(", ")));
+    for i in 1..=1000 {
+        rows(&schema, &format!("INSERT INTO user_lot VALUES ({}, 1, {})", i, i));
+    }
+    let result = rows(
+        &schema,
+        "SELECT user_lot.quantity FROM user_lot, orders WHERE user_lot.user_id = orders.order_id"
+    );
+    assert_eq!(result.len(), 3);
+}
+
+#[test]
+fn left_join_keeps_unmatched_rows_with_nulls() {
+    let schema = test_schema();
+    insert_trading_data(&schema);
+    rows(&schema, "INSERT INTO user_lot VALUES (1, 1, 10), (1, 2, 5), (2, 1, 7)");
+    let result = rows(
+        &schema,
+        "SELECT users.username, user_lot.quantity FROM users \
+         LEFT OUTER JOIN user_lot ON users.user_id = user_lot.user_id AND user_lot.lot_id = 1"
+    );
+    assert_eq!(sorted(result), vec![vec!["10", "alice"], vec!["7", "bob"], vec!["NULL", "carol"]]);
+    let result = rows(
+        &schema,
+        "SELECT user_lot.quantity, users.username FROM user_lot \
+         RIGHT JOIN users ON users.user_id = user_lot.user_id WHERE users.username <> 'alice'"
+    );
+    assert_eq!(sorted(result), vec![vec!["7", "bob"], vec!["NULL", "carol"]]);
+}
+
+#[test]
+fn inner_and_cross_joins() {
+    let schema = test_schema();
+    insert_trading_data(&schema);
+    let result = rows(
+        &schema,
+        "SELECT orders.order_id FROM orders INNER JOIN users ON orders.user_id = users.user_id \
+         JOIN pair ON pair.pair_id = orders.pair_id WHERE users.username = 'bob'"
+    );
+    assert_eq!(result, vec![vec!["2"]]);
+    assert_eq!(rows(&schema, "SELECT lot.name FROM users CROSS JOIN lot").len(), 6);
+    assert!(run(&schema, "SELECT lot.name FROM users JOIN lot").is_err());
+}
+
+#[test]
+fn select_keeps_the_projection_order() {
+    let schema = test_schema();
+    insert_trading_data(&schema);
+    let result = rows(
+        &schema,
+        "SELECT users.username, orders.price, users.user_id, orders.order_id FROM orders, users \
+         WHERE orders.user_id = users.user_id AND orders.order_id = 1"
+    );
+    assert_eq!(result, vec![vec!["alice", "100.5", "1", "1"]]);
+}
+
+#[test]
+fn select_star_expands_tables_in_from_order() {
+    let schema = test_schema();
+    insert_trading_data(&schema);
+    assert_eq!(rows(&schema, "SELECT * FROM lot WHERE lot.lot_id = 2"), vec![vec!["2", "USD"]]);
+    let result = rows(
+        &schema,
+        "SELECT lot.*, pair.buy_lot_id FROM pair JOIN lot ON lot.lot_id = pair.sale_lot_id"
+    );
+    assert_eq!(result, vec![vec!["1", "BTC", "2"]]);
+    let result = rows(&schema, "SELECT * FROM pair, lot WHERE lot.lot_id = pair.buy_lot_id");
+    assert_eq!(result, vec![vec!["1", "1", "2", "2", "USD"]]);
+    assert!(run(&schema, "SELECT users.* FROM lot").is_err());
+}
+
+#[test]
+fn aliases_allow_self_joins() {
+    let schema = test_schema();
+    insert_trading_data(&schema);
+    let result = rows(
+        &schema,
+        "SELECT b.order_id, s.order_id FROM orders b, orders AS s \
+         WHERE b.order_type = 'buy' AND s.order_type = 'sell' AND b.price >= s.price"
+    );
+    assert_eq!(result, vec![vec!["3", "1"]]);
+    let error = run(&schema, "SELECT orders.order_id FROM orders, orders").unwrap_err();
+    assert_eq!(error, "Table name orders specified more than once");
+}
+
+#[test]
+fn bare_columns_resolve_against_from() {
+    let schema = test_schema();
+    insert_trading_data(&schema);
+    let result = rows(
+        &schema,
+        "SELECT username, price FROM orders o JOIN users u ON o.user_id = u.user_id \
+         WHERE order_type = 'buy' AND price > 100"
+    );
+    assert_eq!(result, vec![vec!["carol", "101"]]);
+    let error = run(&schema, "SELECT user_id FROM orders, users").unwrap_err();
+    assert_eq!(error, "Column reference user_id is ambiguous");
+    assert!(run(&schema, "SELECT orders.price FROM orders o").is_err());
+
+    rows(&schema, "DELETE FROM orders WHERE order_type = 'buy'");
+    assert_eq!(rows(&schema, "SELECT order_id FROM orders"), vec![vec!["1"]]);
+}
+
+#[test]
+fn order_by_sorts_by_typed_values() {
+    let schema = test_schema();
+    insert_trading_data(&schema);
+    rows(&schema, "INSERT INTO users VALUES ('dave', 'k4')");
+    rows(&schema, "INSERT INTO orders VALUES (4, 1, 2, 9.5, 'sell', NULL)");
+    // 9.5 < 99 < 100.5 < 101 numerically, not as text
+    let result = rows(&schema, "SELECT order_id FROM orders ORDER BY price");
+    assert_eq!(result, vec![vec!["4"], vec!["2"], vec!["1"], vec!["3"]]);
+    let result = rows(&schema, "SELECT order_id FROM orders ORDER BY order_type DESC, price ASC");
+    assert_eq!(result, vec![vec!["4"], vec!["1"], vec!["2"], vec!["3"]]);
+    // NULLs sort after every value
+    let result = rows(&schema, "SELECT order_id FROM orders ORDER BY closed, order_id DESC");
+    assert_eq!(result, vec![vec!["3"], vec!["4"], vec!["2"], vec!["1"]]);
+}
+
+#[test]
+fn limit_and_offset_page_through_sorted_rows() {
+    let schema = test_schema();
+    insert_trading_data(&schema);
+    for i in 0..200 {
+        rows(&schema, &format!("INSERT INTO user_lot VALUES (1, 1, {})", (i * 37) % 200));
+    }
+    let page = |query: &str| -> Vec<String> {
+        rows(&schema, query)
+            .into_iter()
+            .map(|row| row[0].clone())
+            .collect()
+    };
+    assert_eq!(
+        page("SELECT quantity FROM user_lot ORDER BY quantity DESC LIMIT 3"),
+        vec!["199", "198", "197"]
+    );
+    assert_eq!(
+        page("SELECT quantity FROM user_lot ORDER BY quantity LIMIT 2 OFFSET 10"),
+        vec!["10", "11"]
+    );
+    assert_eq!(page("SELECT quantity FROM user_lot ORDER BY quantity OFFSET 198"), vec!["198", "199"]);
+    assert_eq!(page("SELECT quantity FROM user_lot LIMIT 0").len(), 0);
+    assert_eq!(page("SELECT quantity FROM user_lot LIMIT 5").len(), 5);
+    assert!(run(&schema, "SELECT quantity FROM user_lot LIMIT -1").is_err());
+}
+
+#[test]
+fn aggregates_with_group_by_and_having() {
+    let schema = test_schema();
+    insert_trading_data(&schema);
+    rows(&schema, "INSERT INTO user_lot VALUES (1, 1, 10), (1, 2, 5.5), (2, 1, 7), (2, 1, 3)");
+    let result = rows(
+        &schema,
+        "SELECT user_id, SUM(quantity), COUNT(*), COUNT(DISTINCT lot_id) FROM user_lot \
+         GROUP BY user_id ORDER BY user_id"
+    );
+    assert_eq!(result, vec![vec!["1", "15.5", "2", "2"], vec!["2", "10", "2", "1"]]);
+    let result = rows(
+        &schema,
+        "SELECT u.username, MAX(o.price) FROM users u JOIN orders o ON o.user_id = u.user_id \
+         GROUP BY u.username HAVING COUNT(*) >= 1 AND MIN(o.price) > 99.5 ORDER BY MAX(o.price) DESC"
+    );
+    assert_eq!(result, vec![vec!["carol", "101"], vec!["alice", "100.5"]]);
+    let result = rows(&schema, "SELECT AVG(price), MIN(order_type), COUNT(closed) FROM orders");
+    assert_eq!(result, vec![vec!["100.16666666666667", "buy", "1"]]);
+}
+
+#[test]
+fn aggregates_over_no_rows_and_invalid_use() {
+    let schema = test_schema();
+    let result = rows(&schema, "SELECT COUNT(*), SUM(quantity), MAX(quantity) FROM user_lot");
+    assert_eq!(result, vec![vec!["0", "NULL", "NULL"]]);
+    assert_eq!(rows(&schema, "SELECT user_id, COUNT(*) FROM user_lot GROUP BY user_id").len(), 0);
+
+    let error = run(&schema, "SELECT user_id, COUNT(*) FROM user_lot").unwrap_err();
+    assert_eq!(
+        error,
+        "Column user_lot.user_id must appear in GROUP BY or be used in an aggregate function"
+    );
+    assert!(run(&schema, "SELECT user_id FROM user_lot WHERE SUM(quantity) > 1").is_err());
+    assert!(run(&schema, "SELECT SUM(COUNT(*)) FROM user_lot").is_err());
+    rows(&schema, "INSERT INTO users VALUES ('alice', 'key')");
+    assert!(run(&schema, "SELECT SUM(username) FROM users").is_err());
+}
+
+#[test]
+fn select_distinct_removes_duplicate_rows() {
+    let schema = test_schema();
+    insert_trading_data(&schema);
+    rows(
+        &schema,
+        "INSERT INTO orders VALUES (1, 1, 1, 100, 'buy', NULL), (2, 1, 2, 98, 'buy', NULL)"
+    );
+    let result = rows(&schema, "SELECT DISTINCT pair_id, order_type FROM orders ORDER BY order_type");
+    assert_eq!(result, vec![vec!["1", "buy"], vec!["1", "sell"]]);
+    let result = rows(&schema, "SELECT DISTINCT closed FROM orders ORDER BY closed");
+    assert_eq!(result, vec![vec!["2024-05-01 10:00:00"], vec!["NULL"]]);
+    let result = rows(&schema, "SELECT DISTINCT user_id FROM orders ORDER BY user_id LIMIT 2 OFFSET 1");
+    assert_eq!(result, vec![vec!["2"], vec!["3"]]);
+    let result = rows(&schema, "SELECT COUNT(DISTINCT user_id), SUM(DISTINCT quantity) FROM orders");
+    assert_eq!(result, vec![vec!["3", "19"]]);
+}
+
+#[test]
+fn projection_evaluates_expressions_and_functions() {
+    let schema = test_schema();
+    insert_trading_data(&schema);
+    let result = rows(
+        &schema,
+        "SELECT o.quantity * o.price AS total, UPPER(u.username) || '#' || o.order_id, \
+         LENGTH(u.username), SUBSTR(u.username, 2, 3), -o.quantity + 1 \
+         FROM orders o JOIN users u ON u.user_id = o.user_id ORDER BY total DESC"
+    );
+    assert_eq!(
+        result,
+        vec![
+            vec!["808", "CAROL#3", "5", "aro", "-7"],
+            vec!["502.5", "ALICE#1", "5", "lic", "-4"],
+            vec!["297", "BOB#2", "3", "ob", "-2"]
+        ]
+    );
+    let result = rows(
+        &schema,
+        "SELECT order_id, COALESCE(closed, 'open'), CONCAT(LOWER(order_type), '-', closed), \
+         CASE WHEN price > 100 THEN 'high' WHEN price > 99 THEN 'mid' ELSE 'low' END, \
+         CASE order_type WHEN 'buy' THEN 1 ELSE -1 END \
+         FROM orders ORDER BY order_id"
+    );
+    assert_eq!(
+        result,
+        vec![
+            vec!["1", "open", "sell-", "high", "-1"],
+            vec!["2", "open", "buy-", "low", "1"],
+            vec!["3", "2024-05-01 10:00:00", "buy-2024-05-01 10:00:00", "high", "1"]
+        ]
+    );
+    assert_eq!(rows(&schema, "SELECT 7 / 2, 7 % 4, 1 / 0, 2 - -3 FROM lot LIMIT 1"), vec![
+        vec!["3", "3", "NULL", "5"]
+    ]);
+    let result = rows(&schema, "SELECT SUM(quantity * price) FROM orders WHERE price >= 99 + 1");
+    assert_eq!(result, vec![vec!["1310.5"]]);
+    assert!(run(&schema, "SELECT UPPER(username, 1) FROM users").is_err());
+    assert!(run(&schema, "SELECT NOSUCH(username) FROM users").is_err());
+}
+
+#[test]
+fn in_lists_and_subqueries_in_where() {
+    let schema = test_schema();
+    insert_trading_data(&schema);
+    let result = rows(&schema, "SELECT order_id FROM orders WHERE user_id IN (1, 3) ORDER BY order_id");
+    assert_eq!(result, vec![vec!["1"], vec!["3"]]);
+    let result = rows(&schema, "SELECT order_id FROM orders WHERE order_id NOT IN (1, 2)");
+    assert_eq!(result, vec![vec!["3"]]);
+    assert_eq!(rows(&schema, "SELECT order_id FROM orders WHERE order_id NOT IN (1, NULL)").len(), 0);
+
+    let result = rows(
+        &schema,
+        "SELECT order_id FROM orders WHERE user_id IN \
+         (SELECT user_id FROM users WHERE username LIKE '%o%') ORDER BY order_id"
+    );
+    assert_eq!(result, vec![vec!["2"], vec!["3"]]);
+    let result = rows(&schema, "SELECT order_id FROM orders WHERE price > (SELECT AVG(price) FROM orders)");
+    assert_eq!(result, vec![vec!["1"], vec!["3"]]);
+    // Correlated: users with a buy order, and users with no order above 100
+    let result = rows(
+        &schema,
+        "SELECT username FROM users u WHERE EXISTS \
+         (SELECT * FROM orders WHERE orders.user_id = u.user_id AND order_type = 'buy') ORDER BY username"
+    );
+    assert_eq!(result, vec![vec!["bob"], vec!["carol"]]);
+    let result = rows(
+        &schema,
+        "SELECT username FROM users WHERE NOT EXISTS \
+         (SELECT * FROM orders o WHERE o.user_id = users.user_id AND price > 100)"
+    );
+    assert_eq!(result, vec![vec!["bob"]]);
+    let error = run(&schema, "SELECT username FROM users WHERE user_id = (SELECT user_id FROM orders)");
+    assert_eq!(error.unwrap_err(), "Scalar subquery returned more than one row");
+}
+
+#[test]
+fn scalar_subqueries_in_projection_and_delete() {
+    let schema = test_schema();
+    insert_trading_data(&schema);
+    rows(&schema, "INSERT INTO orders VALUES (1, 1, 1, 90, 'sell', NULL)");
+    let result = rows(
+        &schema,
+        "SELECT username, (SELECT COUNT(*) FROM orders WHERE orders.user_id = users.user_id) \
+         FROM users ORDER BY username"
+    );
+    assert_eq!(result, vec![vec!["alice", "2"], vec!["bob", "1"], vec!["carol", "1"]]);
+
+    rows(
+        &schema,
+        "DELETE FROM orders WHERE user_id IN (SELECT user_id FROM users WHERE username = 'bob')"
+    );
+    assert_eq!(rows(&schema, "SELECT COUNT(*) FROM orders"), vec![vec!["3"]]);
+    // Orders priced above the average of their owner's orders
+    rows(
+        &schema,
+        "DELETE FROM orders WHERE price > \
+         (SELECT AVG(o.price) FROM orders o WHERE o.user_id = orders.user_id)"
+    );
+    let result = rows(&schema, "SELECT order_id FROM orders ORDER BY order_id");
+    assert_eq!(result, vec![vec!["3"], vec!["4"]]);
+    // UPDATE finds its rows through a subquery the same way
+    rows(&schema, "UPDATE orders SET price = 5 WHERE order_id IN (SELECT MIN(order_id) FROM orders)");
+    rows(&schema, "UPDATE orders SET user_id = 2 WHERE price = (SELECT MAX(price) FROM orders)");
+    let result = rows(&schema, "SELECT order_id, user_id, price FROM orders ORDER BY order_id");
+    assert_eq!(result, vec![vec!["3", "3", "5"], vec!["4", "2", "90"]]);
+}
+
+#[test]
+fn nulls_are_stored_apart_from_empty_strings() {
+    let schema = test_schema();
+    insert_trading_data(&schema);
+    rows(&schema, "INSERT INTO users VALUES ('', 'key'), ('dave', 'key')");
+    assert_eq!(rows(&schema, "SELECT user_id FROM users WHERE username = ''"), vec![vec!["4"]]);
+    assert_eq!(rows(&schema, "SELECT user_id FROM users WHERE username IS NULL").len(), 0);
+
+    let block = std::fs::read_to_string(format!("{}/orders/1.csv", schema.name)).unwrap();
+    assert!(block.contains("sell,\\N"));
+    let result = rows(&schema, "SELECT order_id, closed FROM orders WHERE closed IS NULL");
+    assert_eq!(result, vec![vec!["1", "NULL"], vec!["2", "NULL"]]);
+    let result = rows(&schema, "SELECT order_id FROM orders WHERE closed IS NOT NULL");
+    assert_eq!(result, vec![vec!["3"]]);
+}
+
+#[test]
+fn result_rows_keep_nulls_and_text_apart() {
+    let row = [
+        Value::Null,
+        Value::Text("NULL".to_string()),
+        Value::Text("\\N".to_string()),
+        Value::Text(String::new()),
+        Value::Text("Gold, 1oz".to_string()),
+        Value::Text("say \"hi\"\nnow".to_string()),
+        Value::Integer(7),
+        Value::parse("2024-05-01 10:00:00", ColumnType::Timestamp).unwrap(),
+    ];
+    assert_eq!(
+        encode_result_row(&row),
+        "\\N NULL \"\\N\" \"\" \"Gold, 1oz\" \"say \"\"hi\"\"\nnow\" 7 \"2024-05-01 10:00:00\""
+    );
+}
+
+#[test]
+fn conditions_use_three_valued_logic() {
+    let schema = test_schema();
+    insert_trading_data(&schema);
+    // closed > x is unknown for open orders, and so is its negation
+    let result = rows(&schema, "SELECT order_id FROM orders WHERE NOT closed > '2024-01-01'");
+    assert_eq!(result.len(), 0);
+    let result = rows(
+        &schema,
+        "SELECT order_id FROM orders WHERE closed > '2024-01-01' OR order_type = 'sell'"
+    );
+    assert_eq!(result, vec![vec!["1"], vec!["3"]]);
+    let result = rows(
+        &schema,
+        "SELECT order_id FROM orders WHERE NOT (closed > '2024-01-01' AND order_type = 'sell')"
+    );
+    assert_eq!(result, vec![vec!["2"], vec!["3"]]);
+    assert_eq!(rows(&schema, "SELECT order_id FROM orders WHERE closed = NULL").len(), 0);
+    let result = rows(&schema, "SELECT closed IS NULL, closed = NULL FROM orders WHERE order_id = 1");
+    assert_eq!(result, vec![vec!["true", "NULL"]]);
+}
+
+#[test]
+fn text_values_survive_csv_quoting() {
+    let schema = test_schema();
+    let names = ["O'Brien", "\"Gold, 1oz\"", "two\nlines", "  padded  ", "\\N", "a,\"b\"\r\nc"];
+    for name in names.iter() {
+        let literal = name.replace('\'', "''");
+        rows(&schema, &format!("INSERT INTO lot VALUES ('{}')", literal));
+    }
+    let result = rows(&schema, "SELECT name FROM lot ORDER BY lot_id");
+    let expected: Vec<Vec<String>> = names
+        .iter()
+        .map(|name| vec![name.to_string()])
+        .collect();
+    assert_eq!(result, expected);
+    assert_eq!(rows(&schema, "SELECT lot_id FROM lot WHERE name IS NULL").len(), 0);
+
+    // Rewriting the blocks keeps the other records intact
+    rows(&schema, "DELETE FROM lot WHERE name = 'O''Brien'");
+    rows(&schema, "UPDATE lot SET name = 'x,y' WHERE lot_id = 4");
+    let result = rows(&schema, "SELECT name FROM lot ORDER BY lot_id");
+    assert_eq!(result.len(), 5);
+    assert_eq!(result[0][0], "\"Gold, 1oz\"");
+    assert_eq!(result[1][0], "two\nlines");
+    assert_eq!(result[2][0], "x,y");
+    assert_eq!(result[4][0], "a,\"b\"\r\nc");
+}
+
+#[test]
+fn insert_with_column_list_defaults_and_returning() {
+    let schema = test_schema();
+    insert_trading_data(&schema);
+    // user_lot.quantity defaults to 0 in schema.json
+    let result = rows(
+        &schema,
+        "INSERT INTO user_lot (lot_id, user_id) VALUES (2, 1), (1, 3) RETURNING id, user_id, quantity"
+    );
+    assert_eq!(
+        result,
+        vec![
+            vec!["1".to_string(), "1".to_string(), "0".to_string()],
+            vec!["2".to_string(), "3".to_string(), "0".to_string()]
+        ]
+    );
+    let result = rows(&schema, "INSERT INTO user_lot VALUES (2, 2, DEFAULT) RETURNING *");
+    assert_eq!(result, vec![vec!["3".to_string(), "2".to_string(), "2".to_string(), "0".to_string()]]);
+    let result = rows(
+        &schema,
+        "INSERT INTO orders (user_id, pair_id, quantity, price, order_type) VALUES (1, 1, 2, 50, 'buy') RETURNING order_id, quantity * price AS total, closed"
+    );
+    assert_eq!(result, vec![vec!["4".to_string(), "100".to_string(), "NULL".to_string()]]);
+
+    let error = run(&schema, "INSERT INTO users (username) VALUES ('dave')").unwrap_err();
+    assert_eq!(error, "Column users.auth_key cannot be NULL");
+    let error = run(&schema, "INSERT INTO users VALUES ('dave')").unwrap_err();
+    assert_eq!(error, "INSERT has 1 values but 2 columns are expected");
+    let error = run(&schema, "INSERT INTO users (username, auth_key) VALUES ('dave', 'k', 'x')").unwrap_err();
+    assert_eq!(error, "INSERT has 3 values but 2 columns are expected");
+    let error = run(&schema, "INSERT INTO users (user_id, username) VALUES (9, 'dave')").unwrap_err();
+    assert_eq!(error, "Column users.user_id is generated and cannot be inserted");
+    let error = run(&schema, "INSERT INTO users (username, username) VALUES ('a', 'b')").unwrap_err();
+    assert_eq!(error, "Column username specified more than once");
+    let error = run(&schema, "INSERT INTO users (nickname) VALUES ('dave')").unwrap_err();
+    assert_eq!(error, "Column nickname is not in table users");
+    assert_eq!(rows(&schema, "SELECT COUNT(*) FROM users"), vec![vec!["3".to_string()]]);
+}
+
+#[test]
+fn insert_select_copies_rows_with_fresh_keys() {
+    let schema = test_schema();
+    insert_trading_data(&schema);
+    let result = rows(
+        &schema,
+        "INSERT INTO user_lot (user_id, lot_id, quantity) SELECT user_id, 1, price FROM orders WHERE order_type = 'buy' ORDER BY price RETURNING id, quantity"
+    );
+    assert_eq!(
+        result,
+        vec![vec!["1".to_string(), "99".to_string()], vec!["2".to_string(), "101".to_string()]]
+    );
+    // The source rows are read before the copies are written
+    rows(&schema, "INSERT INTO users SELECT username || '2', auth_key FROM users");
+    assert_eq!(
+        rows(&schema, "SELECT user_id, username FROM users WHERE user_id > 3 ORDER BY user_id"),
+        vec![
+            vec!["4".to_string(), "alice2".to_string()],
+            vec!["5".to_string(), "bob2".to_string()],
+            vec!["6".to_string(), "carol2".to_string()]
+        ]
+    );
+
+    let error = run(&schema, "INSERT INTO lot SELECT name, name FROM lot").unwrap_err();
+    assert_eq!(error, "INSERT has 2 values but 1 columns are expected");
+    let error = run(&schema, "INSERT INTO pair SELECT name, name FROM lot").unwrap_err();
+    assert!(error.contains("pair.sale_lot_id"), "{}", error);
+    assert_eq!(rows(&schema, "SELECT COUNT(*) FROM pair"), vec![vec!["1".to_string()]]);
+}
+
+#[test]
+fn transactions_commit_atomically_and_roll_back() {
+    let schema = test_schema();
+    insert_trading_data(&schema);
+    let mut first = Session::new();
+    let mut second = Session::new();
+    let count = |session: &mut Session, query: &str| {
+        run_in(session, &schema, query).unwrap()[0][0].clone()
+    };
+
+    run_in(&mut first, &schema, "BEGIN").unwrap();
+    run_in(&mut first, &schema, "UPDATE orders SET quantity = 0 WHERE order_id = 1").unwrap();
+    run_in(&mut first, &schema, "INSERT INTO user_lot VALUES (1, 1, 5), (1, 2, 500)").unwrap();
+    // Writes are visible inside the transaction only
+    assert_eq!(count(&mut first, "SELECT COUNT(*) FROM user_lot"), "2");
+    assert_eq!(count(&mut second, "SELECT COUNT(*) FROM user_lot"), "0");
+    assert_eq!(count(&mut second, "SELECT quantity FROM orders WHERE order_id = 1"), "5");
+    run_in(&mut first, &schema, "COMMIT").unwrap();
+    assert_eq!(count(&mut second, "SELECT COUNT(*) FROM user_lot"), "2");
+    assert_eq!(count(&mut second, "SELECT quantity FROM orders WHERE order_id = 1"), "0");
+
+    run_in(&mut first, &schema, "BEGIN TRANSACTION").unwrap();
+    run_in(&mut first, &schema, "DELETE FROM user_lot WHERE quantity > 0").unwrap();
+    assert_eq!(count(&mut first, "SELECT COUNT(*) FROM user_lot"), "0");
+    run_in(&mut first, &schema, "ROLLBACK").unwrap();
+    assert_eq!(count(&mut first, "SELECT COUNT(*) FROM user_lot"), "2");
+    // Rows deleted by a transaction that rolled back can change again
+    run_in(&mut second, &schema, "DELETE FROM user_lot WHERE quantity = 5").unwrap();
+    assert_eq!(count(&mut first, "SELECT COUNT(*) FROM user_lot"), "1");
+
+    // A transaction reads the rows committed before it began
+    run_in(&mut first, &schema, "BEGIN").unwrap();
+    assert_eq!(count(&mut first, "SELECT COUNT(*) FROM lot"), "2");
+    run_in(&mut second, &schema, "INSERT INTO lot VALUES ('EUR')").unwrap();
+    assert_eq!(count(&mut first, "SELECT COUNT(*) FROM lot"), "2");
+    run_in(&mut first, &schema, "COMMIT").unwrap();
+    assert_eq!(count(&mut first, "SELECT COUNT(*) FROM lot"), "3");
+
+    // A row changed by a transaction that has not ended cannot change again
+    run_in(&mut first, &schema, "BEGIN").unwrap();
+    run_in(&mut first, &schema, "UPDATE orders SET quantity = 1 WHERE order_id = 1").unwrap();
+    let error = run_in(&mut second, &schema, "DELETE FROM orders WHERE order_id = 1").unwrap_err();
+    assert_eq!(error, "Could not serialize: a row of orders was changed by a concurrent transaction");
+    run_in(&mut second, &schema, "UPDATE orders SET quantity = 2 WHERE order_id = 2").unwrap();
+    run_in(&mut first, &schema, "COMMIT").unwrap();
+    assert_eq!(
+        rows(&schema, "SELECT order_id, quantity FROM orders WHERE order_id < 3 ORDER BY order_id"),
+        vec![vec!["1".to_string(), "1".to_string()], vec!["2".to_string(), "2".to_string()]]
+    );
+
+    assert_eq!(run_in(&mut first, &schema, "COMMIT").unwrap_err(), "No transaction in progress");
+    run_in(&mut first, &schema, "BEGIN").unwrap();
+    assert_eq!(run_in(&mut first, &schema, "BEGIN").unwrap_err(), "A transaction is already in progress");
+}
+
+#[test]
+fn transactions_are_shared_with_other_server_processes() {
+    use std::io::Write;
+    use std::os::unix::io::AsRawFd;
+    let schema = test_schema();
+    insert_trading_data(&schema);
+    let block = format!("{}/lot/1.csv", schema.name);
+    let running = format!("{}/.running", schema.name);
+    std::fs::create_dir_all(&running).unwrap();
+    let count = || rows(&schema, "SELECT COUNT(*) FROM lot")[0][0].clone();
+    // Another process takes the next id and deletes a row with it
+    let take_xid = |name: &str| {
+        let path = format!("{}/.xid", schema.name);
+        let xid: u64 = std::fs::read_to_string(&path).unwrap().trim().parse().unwrap();
+        std::fs::write(&path, format!("{}\n", xid + 1)).unwrap();
+        let content = std::fs::read_to_string(&block).unwrap();
+        std::fs::write(&block, content.replacen(&format!(",0,{}", name), &format!(",{},{}", xid, name), 1)).unwrap();
+        let marker = std::fs::File::create(format!("{}/{}", running, xid)).unwrap();
+        (xid, marker)
+    };
+
+    let (xid, marker) = take_xid("1,BTC");
+    assert_eq!(unsafe { libc::flock(marker.as_raw_fd(), libc::LOCK_EX) }, 0);
+    // The row stays until that transaction commits and cannot change meanwhile
+    assert_eq!(count(), "2");
+    let error = run(&schema, "UPDATE lot SET name = 'XBT' WHERE lot_id = 1").unwrap_err();
+    assert_eq!(error, "Could not serialize: a row of lot was changed by a concurrent transaction");
+    // The ids of this process come after it
+    rows(&schema, "INSERT INTO lot VALUES ('EUR')");
+    let content = std::fs::read_to_string(&block).unwrap();
+    let line = content.lines().find(|line| line.ends_with(",3,EUR")).unwrap();
+    assert!(line.split(',').next().unwrap().parse::<u64>().unwrap() > xid, "{}", line);
+
+    let clog = format!("{}/.clog", schema.name);
+    std::fs::OpenOptions::new().append(true).open(&clog).unwrap().write_all(format!("{}\n", xid).as_bytes()).unwrap();
+    std::fs::remove_file(format!("{}/{}", running, xid)).unwrap();
+    drop(marker);
+    assert_eq!(sorted(rows(&schema, "SELECT name FROM lot")), vec![vec!["EUR"], vec!["USD"]]);
+
+    // A process that stopped without a commit rolled back
+    let (_, marker) = take_xid("2,USD");
+    drop(marker);
+    rows(&schema, "UPDATE lot SET name = 'USDT' WHERE lot_id = 2");
+    assert_eq!(sorted(rows(&schema, "SELECT name FROM lot")), vec![vec!["EUR"], vec!["USDT"]]);
+}
+
+// Row versions stored in the blocks of a table, deleted ones included
+fn stored_versions(schema: &Schema, table: &str) -> usize {
+    let content = std::fs::read_to_string(format!("{}/{}/1.csv", schema.name, table)).unwrap();
+    content.lines().count() - 1
+}
+
+#[test]
+fn new_row_versions_fill_blocks_in_order() {
+    let mut schema = test_schema();
+    schema.tuples_limit = 3;
+    let block_rows = |block: i32| {
+        std::fs::read_to_string(format!("{}/lot/{}.csv", schema.name, block))
+            .map(|content| content.lines().count() - 1)
+            .unwrap_or(0)
+    };
+    rows(&schema, "INSERT INTO lot VALUES ('A'), ('B')");
+    rows(&schema, "INSERT INTO lot VALUES ('C'), ('D'), ('E'), ('F'), ('G'), ('H')");
+    assert_eq!((block_rows(1), block_rows(2), block_rows(3)), (3, 3, 2));
+
+    // The new version goes into the block the statement rewrites, then
+    // into a new one
+    rows(&schema, "UPDATE lot SET name = 'X' WHERE lot_id = 8");
+    rows(&schema, "UPDATE lot SET name = 'Y' WHERE lot_id = 1");
+    assert_eq!((block_rows(3), block_rows(4)), (3, 1));
+    assert_eq!(
+        rows(&schema, "SELECT name FROM lot WHERE lot_id = 1 OR lot_id = 8 ORDER BY lot_id"),
+        vec![vec!["Y"], vec!["X"]]
+    );
+    assert_eq!(rows(&schema, "SELECT COUNT(*) FROM lot"), vec![vec!["8"]]);
+}
+
+#[test]
+fn snapshots_see_old_versions_until_vacuum_removes_them() {
+    let schema = test_schema();
+    insert_trading_data(&schema);
+    let mut reader = Session::new();
+    run_in(&mut reader, &schema, "BEGIN").unwrap();
+    assert_eq!(run_in(&mut reader, &schema, "SELECT COUNT(*) FROM orders").unwrap(), vec![vec!["3"]]);
+
+    rows(&schema, "DELETE FROM orders WHERE order_type = 'buy'");
+    rows(&schema, "UPDATE orders SET quantity = 6 WHERE order_id = 1");
+    assert_eq!(rows(&schema, "SELECT order_id, quantity FROM orders"), vec![vec!["1", "6"]]);
+    assert_eq!(
+        run_in(&mut reader, &schema, "SELECT order_id, quantity FROM orders ORDER BY order_id").unwrap(),
+        vec![vec!["1", "5"], vec!["2", "3"], vec!["3", "8"]]
+    );
+    // The open transaction still needs the old versions
+    rows(&schema, "VACUUM orders");
+    assert_eq!(stored_versions(&schema, "orders"), 4);
+    run_in(&mut reader, &schema, "COMMIT").unwrap();
+    rows(&schema, "VACUUM orders");
+    assert_eq!(stored_versions(&schema, "orders"), 1);
+    assert_eq!(rows(&schema, "SELECT order_id, quantity FROM orders"), vec![vec!["1", "6"]]);
+
+    // Versions of a transaction that rolled back are removed as well
+    run_in(&mut reader, &schema, "BEGIN").unwrap();
+    run_in(&mut reader, &schema, "INSERT INTO lot VALUES ('ETH'), ('EUR')").unwrap();
+    run_in(&mut reader, &schema, "ROLLBACK").unwrap();
+    assert_eq!(stored_versions(&schema, "lot"), 4);
+    rows(&schema, "VACUUM");
+    assert_eq!(stored_versions(&schema, "lot"), 2);
+    // Transactions before it are not tracked again after a restart
+    let next_xid = std::fs::read_to_string(format!("{}/.xid", schema.name)).unwrap();
+    assert_eq!(std::fs::read_to_string(format!("{}/.frozen", schema.name)).unwrap(), next_xid);
+    assert_eq!(run(&schema, "VACUUM nosuch").unwrap_err(), "No such table in DB: nosuch");
+}
+
+#[test]
+fn primary_keys_are_reserved_once_across_connections() {
+    let schema = test_schema();
+    std::thread::scope(|scope| {
+        for thread in 0..4 {
+            let schema = &schema;
+            scope.spawn(move || {
+                let mut session = Session::new();
+                for i in 0..5 {
+                    let query = format!("INSERT INTO lot VALUES ('A{}{}'), ('B{}{}')", thread, i, thread, i);
+                    run_in(&mut session, schema, &query).unwrap();
+                }
+            });
+        }
+    });
+    let mut ids: Vec<i64> = rows(&schema, "SELECT lot_id FROM lot")
+        .iter()
+        .map(|row| row[0].parse().unwrap())
+        .collect();
+    ids.sort();
+    assert_eq!(ids, (1..=40).collect::<Vec<i64>>());
+
+    // Keys go beyond the range of i32
+    let sequence = format!("{}/lot/lot_pk_sequence", schema.name);
+    std::fs::write(&sequence, "2147483647\n").unwrap();
+    assert_eq!(
+        rows(&schema, "INSERT INTO lot VALUES ('C'), ('D') RETURNING lot_id"),
+        vec![vec!["2147483648"], vec!["2147483649"]]
+    );
+    assert_eq!(std::fs::read_to_string(&sequence).unwrap(), "2147483649\n");
+    std::fs::write(&sequence, "x\n").unwrap();
+    let error = run(&schema, "INSERT INTO lot VALUES ('E')").unwrap_err();
+    assert_eq!(error, format!("Invalid number in {}", sequence));
+}
+
+#[test]
+fn unique_constraints_reject_duplicate_keys() {
+    let schema = test_schema();
+    insert_trading_data(&schema);
+    let count = |table: &str| rows(&schema, &format!("SELECT COUNT(*) FROM {}", table))[0][0].clone();
+
+    let error = run(&schema, "INSERT INTO users VALUES ('dave', 'k4'), ('alice', 'k5')").unwrap_err();
+    assert_eq!(error, "users: Duplicate key (username)=(alice) violates unique constraint users_username_key");
+    let error = run(&schema, "INSERT INTO users VALUES ('dave', 'k4'), ('dave', 'k5')").unwrap_err();
+    assert_eq!(error, "users: Duplicate key (username)=(dave) violates unique constraint users_username_key");
+    assert_eq!(count("users"), "3");
+    let error = run(&schema, "INSERT INTO pair VALUES (1, 2)").unwrap_err();
+    assert_eq!(
+        error,
+        "pair: Duplicate key (sale_lot_id, buy_lot_id)=(1, 2) violates unique constraint pair_sale_lot_id_buy_lot_id_key"
+    );
+    rows(&schema, "INSERT INTO pair VALUES (2, 1)");
+
+    // A key is free again once the row holding it changes
+    let error = run(&schema, "UPDATE users SET username = 'bob' WHERE user_id = 1").unwrap_err();
+    assert_eq!(error, "users: Duplicate key (username)=(bob) violates unique constraint users_username_key");
+    rows(&schema, "UPDATE users SET auth_key = 'k0' WHERE user_id = 1");
+    rows(&schema, "UPDATE users SET username = 'alex' WHERE user_id = 1");
+    rows(&schema, "DELETE FROM users WHERE username = 'bob'");
+    rows(&schema, "INSERT INTO users VALUES ('alice', 'k4'), ('bob', 'k5')");
+    assert_eq!(count("users"), "4");
+
+    // Keys of transactions that have not ended are taken until they roll back
+    let mut first = Session::new();
+    run_in(&mut first, &schema, "BEGIN").unwrap();
+    run_in(&mut first, &schema, "INSERT INTO lot VALUES ('ETH')").unwrap();
+    let error = run(&schema, "INSERT INTO lot VALUES ('ETH')").unwrap_err();
+    assert_eq!(error, "lot: Duplicate key (name)=(ETH) violates unique constraint lot_name_key");
+    run_in(&mut first, &schema, "ROLLBACK").unwrap();
+    rows(&schema, "INSERT INTO lot VALUES ('ETH')");
+    // Nor are the keys of a statement that failed
+    assert!(run(&schema, "INSERT INTO lot VALUES ('GBP'), ('ETH')").is_err());
+    rows(&schema, "INSERT INTO lot VALUES ('GBP')");
+
+    // The primary key holds even when the sequence is set back
+    std::fs::write(format!("{}/lot/lot_pk_sequence", schema.name), "1\n").unwrap();
+    let error = run(&schema, "INSERT INTO lot VALUES ('EUR')").unwrap_err();
+    assert_eq!(error, "lot: Duplicate key (lot_id)=(2) violates primary key lot_pk");
+}
+
+#[test]
+fn indexes_are_built_from_the_table_and_vacuumed() {
+    let schema = test_schema();
+    insert_trading_data(&schema);
+    let index = format!("{}/users/users_username_key", schema.name);
+    let entries = || std::fs::read_to_string(&index).unwrap().lines().count() - 1;
+    rows(&schema, "UPDATE users SET auth_key = 'k0'");
+    assert_eq!(entries(), 6);
+    rows(&schema, "VACUUM users");
+    assert_eq!(entries(), 3);
+
+    // An index that is missing or made for other columns is built again
+    std::fs::write(&index, "0\n").unwrap();
+    std::fs::remove_file(format!("{}/users/users_pk", schema.name)).unwrap();
+    init_db(&schema);
+    assert_eq!(entries(), 3);
+    assert_eq!(std::fs::read_to_string(&index).unwrap().lines().next(), Some("xmin,xmax,username"));
+    let error = run(&schema, "INSERT INTO users VALUES ('carol', 'k4')").unwrap_err();
+    assert!(error.contains("users_username_key"), "{}", error);
+
+    // Rows that break a constraint stop the server from starting
+    let mut duplicated = schema.clone();
+    duplicated.constraints.get_mut("users").unwrap().unique.push(vec!["auth_key".to_string()]);
+    let error = std::panic::catch_unwind(|| init_db(&duplicated)).unwrap_err();
+    let message = error.downcast_ref::<String>().unwrap();
+    assert!(message.contains("Duplicate key (auth_key)=(k0) violates unique constraint users_auth_key_key"), "{}", message);
+}
+
+#[test]
+fn foreign_keys_check_references_and_apply_on_delete() {
+    let schema = test_schema();
+    insert_trading_data(&schema);
+    rows(&schema, "INSERT INTO user_lot VALUES (1, 2, 10), (2, 1, 20)");
+    let count = |table: &str| rows(&schema, &format!("SELECT COUNT(*) FROM {}", table))[0][0].clone();
+
+    let error = run(&schema, "INSERT INTO orders VALUES (1, 9, 5, 100, 'sell', NULL)").unwrap_err();
+    assert_eq!(
+        error,
+        "Insert or update on table orders violates foreign key orders_pair_id_fkey: key (pair_id)=(9) is not present in table pair"
+    );
+    let error = run(&schema, "UPDATE user_lot SET lot_id = 7 WHERE user_id = 1").unwrap_err();
+    assert!(error.contains("user_lot_lot_id_fkey: key (lot_id)=(7) is not present in table lot"), "{}", error);
+
+    // RESTRICT keeps referenced rows and their keys
+    let error = run(&schema, "DELETE FROM pair WHERE pair_id = 1").unwrap_err();
+    assert_eq!(
+        error,
+        "Delete on table pair violates foreign key orders_pair_id_fkey: key (pair_id)=(1) is still referenced from table orders"
+    );
+    assert_eq!(count("pair"), "1");
+    // A key of other columns than the generated one can be changed by UPDATE
+    let mut referenced = schema.clone();
+    referenced.constraints.get_mut("pair").unwrap().unique.push(vec!["sale_lot_id".to_string()]);
+    referenced.constraints.get_mut("orders").unwrap().foreign_keys[1].referenced_columns =
+        Some(vec!["sale_lot_id".to_string()]);
+    let error = run(&referenced, "UPDATE pair SET sale_lot_id = 2 WHERE pair_id = 1").unwrap_err();
+    assert_eq!(
+        error,
+        "Update on table pair violates foreign key orders_pair_id_fkey: key (sale_lot_id)=(1) is still referenced from table orders"
+    );
+    rows(&schema, "UPDATE users SET auth_key = 'k0' WHERE user_id = 2");
+
+    // CASCADE removes the rows that refer to a deleted row
+    rows(&schema, "DELETE FROM users WHERE user_id = 1");
+    assert_eq!(sorted(rows(&schema, "SELECT user_id FROM orders")), vec![vec!["2"], vec!["3"]]);
+    assert_eq!(rows(&schema, "SELECT user_id FROM user_lot"), vec![vec!["2"]]);
+
+    // SET NULL keeps them with the reference cleared
+    let mut nulled = schema.clone();
+    nulled.structure.get_mut("user_lot").unwrap()[1].nullable = true;
+    nulled.constraints.get_mut("user_lot").unwrap().foreign_keys[0].on_delete = OnDelete::SetNull;
+    rows(&nulled, "DELETE FROM users WHERE user_id = 2");
+    assert_eq!(rows(&nulled, "SELECT user_id, quantity FROM user_lot"), vec![vec!["NULL", "20"]]);
+    assert_eq!(rows(&nulled, "SELECT user_id FROM orders"), vec![vec!["3"]]);
+
+    // RESTRICT lets go of a row the same statement deletes through CASCADE
+    let mut cascaded = schema.clone();
+    cascaded.constraints.get_mut("pair").unwrap().foreign_keys[1].on_delete = OnDelete::Cascade;
+    rows(&cascaded, "INSERT INTO lot VALUES ('ETH')");
+    rows(&cascaded, "INSERT INTO pair VALUES (3, 3)");
+    rows(&cascaded, "DELETE FROM lot WHERE lot_id = 3");
+    assert_eq!(count("pair"), "1");
+    let error = run(&cascaded, "DELETE FROM lot WHERE lot_id = 1").unwrap_err();
+    assert!(error.contains("pair_sale_lot_id_fkey: key (lot_id)=(1) is still referenced"), "{}", error);
+}
+
+#[test]
+fn write_ahead_log_is_replayed_at_startup() {
+    let schema = test_schema();
+    insert_trading_data(&schema);
+    let block = format!("{}/lot/1.csv", schema.name);
+    let sequence = format!("{}/lot/lot_pk_sequence", schema.name);
+
+    // The server stopped after logging a change but before applying it,
+    // in the middle of logging the next one
+    let mut writes = MyVec::new();
+    writes.push(FileWrite::append(block.clone(), b"3,ETH\n".to_vec()));
+    writes.push(FileWrite::replace(sequence.clone(), b"3\n".to_vec()));
+    wal::log(&schema.name, writes).unwrap();
+    let mut torn = MyVec::new();
+    torn.push(FileWrite::replace(block.clone(), b"lot_id,name\n".to_vec()));
+    wal::log(&schema.name, torn).unwrap();
+    let log = wal::wal_path(&schema.name);
+    let length = std::fs::metadata(&log).unwrap().len();
+    std::fs::OpenOptions::new().write(true).open(&log).unwrap().set_len(length - 3).unwrap();
+    assert_eq!(rows(&schema, "SELECT COUNT(*) FROM lot"), vec![vec!["2".to_string()]]);
+
+    init_db(&schema);
+    assert_eq!(
+        rows(&schema, "SELECT lot_id, name FROM lot ORDER BY lot_id"),
+        vec![
+            vec!["1".to_string(), "BTC".to_string()],
+            vec!["2".to_string(), "USD".to_string()],
+            vec!["3".to_string(), "ETH".to_string()]
+        ]
+    );
+    assert_eq!(std::fs::metadata(&log).unwrap().len(), 0);
+    // Replaying again changes nothing
+    init_db(&schema);
+    rows(&schema, "INSERT INTO lot VALUES ('EUR')");
+    assert_eq!(rows(&schema, "SELECT MAX(lot_id), COUNT(*) FROM lot"), vec![vec!["4".to_string(), "4".to_string()]]);
+
+    // A logged write that cannot be replayed is not dropped
+    let lost = format!("{}/gone/1.csv", schema.name);
+    let mut writes = MyVec::new();
+    writes.push(FileWrite::append(lost.clone(), b"1,1,x\n".to_vec()));
+    wal::log(&schema.name, writes).unwrap();
+    let error = wal::recover(&schema.name).unwrap_err();
+    assert!(error.starts_with(&format!("Failed to replay a write to {}", lost)), "{}", error);
+}
+
+#[test]
+fn write_ahead_log_is_held_for_a_whole_change() {
+    use std::os::unix::io::AsRawFd;
+    let schema = test_schema();
+    insert_trading_data(&schema);
+    let block = format!("{}/lot/1.csv", schema.name);
+    let before = std::fs::read_to_string(&block).unwrap();
+    let log = wal::wal_path(&schema.name);
+
+    // A change whose write fails is taken back from the files it did write
+    let mut writes = MyVec::new();
+    writes.push(FileWrite::append(block.clone(), b"3,ETH\n".to_vec()));
+    writes.push(FileWrite::replace(format!("{}/gone/1.csv", schema.name), b"1\n".to_vec()));
+    let error = wal::apply(&schema.name, writes).unwrap_err();
+    assert!(error.starts_with(&format!("Failed to write {}/gone/1.csv", schema.name)), "{}", error);
+    assert_eq!(std::fs::read_to_string(&block).unwrap(), before);
+    assert_eq!(std::fs::metadata(&log).unwrap().len(), 0);
+
+    // A change left in the log is finished before the next one
+    let notes = format!("{}/notes", schema.name);
+    let mut writes = MyVec::new();
+    writes.push(FileWrite::append(notes.clone(), b"a\n".to_vec()));
+    wal::log(&schema.name, writes).unwrap();
+    let mut writes = MyVec::new();
+    writes.push(FileWrite::append(notes.clone(), b"b\n".to_vec()));
+    wal::apply(&schema.name, writes).unwrap();
+    assert_eq!(std::fs::read_to_string(&notes).unwrap(), "a\nb\n");
+
+    // Another process holding the log makes the change wait
+    let held = std::fs::File::open(&log).unwrap();
+    assert_eq!(unsafe { libc::flock(held.as_raw_fd(), libc::LOCK_EX) }, 0);
+    let database = schema.name.clone();
+    let path = notes.clone();
+    let writer = std::thread::spawn(move || {
+        let mut writes = MyVec::new();
+        writes.push(FileWrite::append(path, b"c\n".to_vec()));
+        wal::apply(&database, writes).unwrap();
+    });
+    std::thread::sleep(std::time::Duration::from_millis(100));
+    assert_eq!(std::fs::read_to_string(&notes).unwrap(), "a\nb\n");
+    drop(held);
+    writer.join().unwrap();
+    assert_eq!(std::fs::read_to_string(&notes).unwrap(), "a\nb\nc\n");
+}
+
+#[test]
+fn table_locks_make_statements_wait_or_time_out() {
+    let mut schema = test_schema();
+    schema.lock_timeout_ms = 100;
+    insert_trading_data(&schema);
+    let lock = |table: &str, mode: LockMode| {
+        let mut requests = MyVec::new();
+        requests.push((table.to_string(), mode));
+        lock_tables(&schema, &requests).unwrap()
+    };
+
+    // Readers never wait, writers wait for the tables they change or read
+    let writer = lock("lot", LockMode::Exclusive);
+    assert_eq!(rows(&schema, "SELECT COUNT(*) FROM lot"), vec![vec!["2".to_string()]]);
+    let error = run(&schema, "DELETE FROM users WHERE user_id IN (SELECT lot_id FROM lot)").unwrap_err();
+    assert_eq!(error, "Timed out after 100 ms waiting for a lock on table lot");
+    rows(&schema, "DELETE FROM users WHERE user_id = 3");
+    assert_eq!(rows(&schema, "SELECT COUNT(*) FROM users"), vec![vec!["2".to_string()]]);
+    drop(writer);
+
+    // Readers share a table, writers wait for them
+    let reader = lock("lot", LockMode::Shared);
+    assert_eq!(rows(&schema, "SELECT COUNT(*) FROM lot"), vec![vec!["2".to_string()]]);
+    let error = run(&schema, "INSERT INTO lot VALUES ('ETH')").unwrap_err();
+    assert_eq!(error, "Timed out after 100 ms waiting for a lock on table lot");
+    drop(reader);
+    schema.lock_timeout_ms = 5000;
+    let schema = &schema;
+    let (locked, wait) = std::sync::mpsc::channel();
+    std::thread::scope(|scope| {
+        scope.spawn(move || {
+            let mut requests = MyVec::new();
+            requests.push(("lot".to_string(), LockMode::Shared));
+            let reader = lock_tables(schema, &requests).unwrap();
+            locked.send(()).unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            drop(reader);
+        });
+        wait.recv().unwrap();
+        rows(schema, "INSERT INTO lot VALUES ('ETH')");
+    });
+    assert_eq!(rows(schema, "SELECT COUNT(*) FROM lot"), vec![vec!["3".to_string()]]);
+}
+
+#[test]
+fn cloned_vector_owns_its_elements() {
+    let mut original: crate::MyVec<String> = crate::MyVec::new();
+    original.push("first".to_string());
+    original.push("second".to_string());
+    let copy = original.clone();
+    drop(original);
+    assert_eq!(copy.len(), 2);
+    assert_eq!(copy[0], "first");
+    assert_eq!(copy[1], "second");
+}
+
+#[test]
+fn dropped_vector_drops_every_element() {
+    let item = std::rc::Rc::new(0);
+    let mut vec = crate::MyVec::new();
+    vec.push(std::rc::Rc::clone(&item));
+    vec.push(std::rc::Rc::clone(&item));
+    drop(vec);
+    assert_eq!(std::rc::Rc::strong_count(&item), 1);
+}
@@ -0,0 +1,489 @@
+use std::collections::{ HashMap, HashSet };
+use std::fs::{ self, OpenOptions };
+use std::io::{ self, Read, Seek, SeekFrom };
+use std::os::unix::fs::{ FileExt, MetadataExt };
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::{ Mutex, OnceLock };
+use crate::{ MyVec, Schema };
+use crate::structs::{ DbResponse, RowVersion };
+use crate::lock::{ lock_tables, LockMode };
+use crate::utils::rewrite_table_blocks;
+use crate::constraint::table_indexes;
+use crate::wal::{ self, FileWrite };
+
+// Multi-version concurrency control. Every row version in a block carries
+// the id of the transaction that created it (xmin) and of the one that
+// deleted it (xmax, 0 while the version is live); UPDATE deletes the old
+// version and adds a new one. A transaction reads through a snapshot taken
+// when it began and sees the versions of the transactions that committed
+// before that, plus its own. Readers take no locks, writers lock the table
+// they change for the length of a statement, and a row deleted by another
+// transaction that has not rolled back cannot be changed again.
+//
+// Transaction ids come from `{database}/.xid`, which is read and bumped
+// while the write-ahead log is held, so server processes sharing the
+// database never hand out the same id. The ids of committed transactions
+// are appended to `{database}/.clog`. A running transaction holds an
+// advisory lock on `{database}/.running/{xid}`, and a snapshot in use holds
+// one on `{database}/.running/s{pid}_{n}` that names the oldest transaction
+// the snapshot may not see. An id that is neither committed nor held
+// belongs to a transaction that rolled back or was cut short by a crash,
+// the lock goes away with the process. Transactions below the id in
+// `{database}/.frozen` have all ended and VACUUM has removed the versions
+// of those that rolled back, so they are not tracked any more.
+
+fn xid_path(database: &str) -> String {
+    format!("{}/.xid", database)
+}
+
+fn clog_path(database: &str) -> String {
+    format!("{}/.clog", database)
+}
+
+fn frozen_path(database: &str) -> String {
+    format!("{}/.frozen", database)
+}
+
+fn running_dir(database: &str) -> String {
+    format!("{}/.running", database)
+}
+
+fn read_xid(path: &str, default: u64) -> Result<u64, String> {
+    match fs::read_to_string(path) {
+        Ok(content) =>
+            content
+                .trim()
+                .parse::<u64>()
+                .map_err(|_| format!("Invalid transaction id in {}", path)),
+        Err(_) => Ok(default),
+    }
+}
+
+// What this process has read of the transaction state of a database. The
+// commit log only grows, so only the lines added since are read again.
+struct TransactionLog {
+    committed: HashSet<u64>,
+    // Inode of the commit log and how much of it has been read
+    clog_inode: u64,
+    clog_read: u64,
+    frozen: u64,
+    // Transactions known to have rolled back, they stay so
+    aborted: HashSet<u64>,
+    next_snapshot: u64,
+}
+
+impl TransactionLog {
+    fn new() -> TransactionLog {
+        TransactionLog {
+            committed: HashSet::new(),
+            clog_inode: 0,
+            clog_read: 0,
+            frozen: 1,
+            aborted: HashSet::new(),
+            next_snapshot: 1,
+        }
+    }
+
+    // Catch up with the commits and VACUUMs of all processes
+    fn refresh(&mut self, database: &str) -> Result<(), String> {
+        self.frozen = read_xid(&frozen_path(database), 1)?;
+        let path = clog_path(database);
+        let metadata = match fs::metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(_) => {
+                return Ok(());
+            }
+        };
+        if metadata.ino() != self.clog_inode || metadata.len() < self.clog_read {
+            self.committed.clear();
+            self.clog_inode = metadata.ino();
+            self.clog_read = 0;
+        }
+        if metadata.len() == self.clog_read {
+            return Ok(());
+        }
+
+        let mut content = Vec::new();
+        fs::File::open(&path)
+            .and_then(|mut file| {
+                file.seek(SeekFrom::Start(self.clog_read))?;
+                file.read_to_end(&mut content)
+            })
+            .map_err(|e| format!("Failed to read {}: {}", path, e))?;
+        // A line that is being appended is read the next time
+        let complete = match content.iter().rposition(|byte| *byte == b'\n') {
+            Some(position) => position + 1,
+            None => {
+                return Ok(());
+            }
+        };
+        for line in String::from_utf8_lossy(&content[..complete]).lines() {
+            if let Ok(xid) = line.trim().parse() {
+                self.committed.insert(xid);
+            }
+        }
+        self.clog_read += complete as u64;
+        Ok(())
+    }
+
+    fn is_committed(&mut self, database: &str, xid: u64) -> Result<bool, String> {
+        if xid < self.frozen || self.committed.contains(&xid) {
+            return Ok(true);
+        }
+        self.refresh(database)?;
+        Ok(xid < self.frozen || self.committed.contains(&xid))
+    }
+
+    fn is_aborted(&mut self, database: &str, xid: u64) -> Result<bool, String> {
+        if self.aborted.contains(&xid) {
+            return Ok(true);
+        }
+        if xid == 0 || self.is_committed(database, xid)? || is_running(database, xid) {
+            return Ok(false);
+        }
+        // The transaction may have committed just before it let go of its id
+        self.refresh(database)?;
+        if self.committed.contains(&xid) {
+            return Ok(false);
+        }
+        self.aborted.insert(xid);
+        Ok(true)
+    }
+}
+
+// Transaction state of every database as this process knows it
+fn with_log<T>(database: &str, f: impl FnOnce(&mut TransactionLog) -> Result<T, String>) -> Result<T, String> {
+    static LOGS: OnceLock<Mutex<HashMap<String, TransactionLog>>> = OnceLock::new();
+    let mut logs = match LOGS.get_or_init(|| Mutex::new(HashMap::new())).lock() {
+        Ok(logs) => logs,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    f(logs.entry(database.to_string()).or_insert_with(TransactionLog::new))
+}
+
+// Whether the transaction rolled back, its changes are void
+pub fn is_aborted(database: &str, xid: u64) -> bool {
+    with_log(database, |log| log.is_aborted(database, xid)).unwrap_or(false)
+}
+
+// Whether the transaction committed, its changes are there to stay
+pub fn is_committed(database: &str, xid: u64) -> bool {
+    with_log(database, |log| log.is_committed(database, xid)).unwrap_or(false)
+}
+
+// A file in `{database}/.running` its owner holds an advisory lock on for
+// as long as it runs, removed when dropped
+struct Marker {
+    path: String,
+    file: fs::File,
+}
+
+impl Marker {
+    // The file is locked under another name first, so it is never seen
+    // without its owner
+    fn hold(database: &str, name: &str) -> Result<Marker, String> {
+        let path = format!("{}/{}", running_dir(database), name);
+        let temporary = format!("{}.tmp", path);
+        let open = || OpenOptions::new().create(true).truncate(true).write(true).open(&temporary);
+        let file = match open() {
+            Err(e) if e.kind() == io::ErrorKind::NotFound =>
+                fs::create_dir_all(running_dir(database)).and_then(|_| open()),
+            opened => opened,
+        };
+        let file = file.map_err(|e| format!("Failed to create {}: {}", temporary, e))?;
+        // SAFETY: flock only takes the descriptor of the file opened above
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            return Err(format!("Failed to lock {}: {}", temporary, io::Error::last_os_error()));
+        }
+        fs::rename(&temporary, &path).map_err(|e| format!("Failed to create {}: {}", path, e))?;
+        Ok(Marker { path, file })
+    }
+
+    // Leave a note for the other processes
+    fn note(&self, content: &str) -> Result<(), String> {
+        self.file
+            .write_all_at(content.as_bytes(), 0)
+            .map_err(|e| format!("Failed to write {}: {}", self.path, e))
+    }
+}
+
+impl Drop for Marker {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// The note of a marker whose owner still holds it
+fn held(path: &Path) -> Option<String> {
+    let mut file = fs::File::open(path).ok()?;
+    // SAFETY: flock only takes the descriptor of the file opened above
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_SH | libc::LOCK_NB) } == 0 {
+        return None;
+    }
+    let mut content = String::new();
+    let _ = file.read_to_string(&mut content);
+    Some(content)
+}
+
+fn is_running(database: &str, xid: u64) -> bool {
+    held(Path::new(&format!("{}/{}", running_dir(database), xid))).is_some()
+}
+
+// Ids of the running transactions, and for every snapshot in use the
+// oldest transaction it may not see
+fn held_markers(database: &str) -> (MyVec<u64>, MyVec<u64>) {
+    let mut running = MyVec::new();
+    let mut snapshots = MyVec::new();
+    let entries = match fs::read_dir(running_dir(database)) {
+        Ok(entries) => entries,
+        Err(_) => {
+            return (running, snapshots);
+        }
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.ends_with(".tmp") {
+            continue;
+        }
+        let note = match held(&entry.path()) {
+            Some(note) => note,
+            None => {
+                continue;
+            }
+        };
+        if name.starts_with('s') {
+            // A snapshot that is being taken has no note yet
+            snapshots.push(note.trim().parse().unwrap_or(0));
+        } else if let Ok(xid) = name.parse() {
+            running.push(xid);
+        }
+    }
+    (running, snapshots)
+}
+
+// The row versions a statement can see
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    // Transaction of the statement, set once it writes
+    pub xid: Option<u64>,
+    // Transactions from this id on began after the snapshot was taken
+    next: u64,
+    // Older transactions that had not committed
+    hidden: HashSet<u64>,
+}
+
+impl Snapshot {
+    fn sees(&self, xid: u64) -> bool {
+        xid == 0 || Some(xid) == self.xid || (xid < self.next && !self.hidden.contains(&xid))
+    }
+
+    pub fn sees_version(&self, version: &RowVersion) -> bool {
+        self.sees(version.xmin) && (version.xmax == 0 || !self.sees(version.xmax))
+    }
+}
+
+struct Transaction {
+    database: String,
+    snapshot: Snapshot,
+    // Held while the snapshot is in use
+    _reader: Marker,
+    // Held while the transaction runs, a transaction that lets go of it
+    // without a commit has rolled back
+    writer: Option<Marker>,
+}
+
+impl Transaction {
+    fn begin(database: &str) -> Result<Transaction, String> {
+        // The snapshot is held before it is taken, so VACUUM keeps what it
+        // may need meanwhile
+        let id = with_log(database, |log| {
+            log.next_snapshot += 1;
+            Ok(log.next_snapshot - 1)
+        })?;
+        let reader = Marker::hold(database, &format!("s{}_{}", std::process::id(), id))?;
+        let next = read_xid(&xid_path(database), 1)?;
+        let hidden = with_log(database, |log| {
+            log.refresh(database)?;
+            Ok((log.frozen..next).filter(|xid| !log.committed.contains(xid)).collect::<HashSet<u64>>())
+        })?;
+        let oldest = hidden
+            .iter()
+            .copied()
+            .filter(|xid| !is_aborted(database, *xid))
+            .min()
+            .unwrap_or(next);
+        reader.note(&format!("{}\n", oldest))?;
+        Ok(Transaction {
+            database: database.to_string(),
+            snapshot: Snapshot { xid: None, next, hidden },
+            _reader: reader,
+            writer: None,
+        })
+    }
+
+    // A transaction gets its id when it first writes
+    fn assign_xid(&mut self) -> Result<(), String> {
+        if self.snapshot.xid.is_some() {
+            return Ok(());
+        }
+        let database = self.database.clone();
+        let (xid, writer) = wal::apply_prepared(&database, || {
+            let xid = read_xid(&xid_path(&database), 1)?;
+            // Held before the id is handed out, so nobody takes the
+            // transaction for one that rolled back
+            let writer = Marker::hold(&database, &xid.to_string())?;
+            let mut writes = MyVec::new();
+            writes.push(FileWrite::replace(xid_path(&database), format!("{}\n", xid + 1).into_bytes()));
+            Ok((writes, (xid, writer)))
+        })?;
+        self.snapshot.xid = Some(xid);
+        self.writer = Some(writer);
+        Ok(())
+    }
+
+    // The transaction lets go of its id once its id is in the log
+    fn commit(self) -> Result<(), String> {
+        if let Some(xid) = self.snapshot.xid {
+            let mut writes = MyVec::new();
+            writes.push(FileWrite::append(clog_path(&self.database), format!("{}\n", xid).into_bytes()));
+            wal::apply(&self.database, writes)?;
+        }
+        Ok(())
+    }
+}
+
+// State of one client connection
+#[derive(Default)]
+pub struct Session {
+    transaction: Option<Transaction>,
+}
+
+impl Session {
+    pub fn new() -> Session {
+        Session { transaction: None }
+    }
+
+    pub fn begin(&mut self, schema: &Schema) -> Result<(), String> {
+        if self.transaction.is_some() {
+            return Err("A transaction is already in progress".to_string());
+        }
+        self.transaction = Some(Transaction::begin(&schema.name)?);
+        Ok(())
+    }
+
+    pub fn commit(&mut self) -> Result<(), String> {
+        match self.transaction.take() {
+            Some(transaction) => transaction.commit(),
+            None => Err("No transaction in progress".to_string()),
+        }
+    }
+
+    pub fn rollback(&mut self) -> Result<(), String> {
+        match self.transaction.take() {
+            Some(_) => Ok(()),
+            None => Err("No transaction in progress".to_string()),
+        }
+    }
+
+    // Run a statement in the open transaction, or in a transaction of its
+    // own that commits if the statement succeeds
+    pub fn run<F>(&mut self, schema: &Schema, writes: bool, statement: F) -> DbResponse
+        where F: FnOnce(&Snapshot) -> DbResponse
+    {
+        let mut own = None;
+        let transaction = match self.transaction.as_mut() {
+            Some(transaction) => transaction,
+            None =>
+                match Transaction::begin(&schema.name) {
+                    Ok(transaction) => own.insert(transaction),
+                    Err(e) => {
+                        return DbResponse::Error(e);
+                    }
+                }
+        };
+        if writes {
+            if let Err(e) = transaction.assign_xid() {
+                return DbResponse::Error(e);
+            }
+        }
+        let response = statement(&transaction.snapshot);
+        match (own, &response) {
+            (Some(transaction), DbResponse::Success(_)) =>
+                match transaction.commit() {
+                    Ok(()) => response,
+                    Err(e) => DbResponse::Error(e),
+                }
+            _ => response,
+        }
+    }
+}
+
+// Remove the row versions no transaction can see any more: those of
+// transactions that rolled back and those deleted before every snapshot
+// in use was taken
+pub fn vacuum(schema: &Schema, table: Option<&str>) -> Result<(), String> {
+    let tables: MyVec<String> = match table {
+        Some(table) if schema.structure.contains_key(table) => {
+            let mut tables = MyVec::new();
+            tables.push(table.to_string());
+            tables
+        }
+        Some(table) => {
+            return Err(format!("No such table in DB: {}", table));
+        }
+        None => schema.structure.keys().cloned().collect(),
+    };
+    // Ids handed out later are not in the markers or the blocks yet
+    let next = read_xid(&xid_path(&schema.name), 1)?;
+    let (running, snapshots) = held_markers(&schema.name);
+    // Every transaction below this id has ended
+    let ended = running.iter().min().copied().unwrap_or(next).min(next);
+    // and every snapshot in use sees it as ended
+    let horizon = snapshots.iter().min().copied().unwrap_or(u64::MAX).min(ended);
+    let frozen = with_log(&schema.name, |log| {
+        log.refresh(&schema.name)?;
+        Ok(log.frozen)
+    })?;
+    let aborted: HashSet<u64> = (frozen..next).filter(|xid| is_aborted(&schema.name, *xid)).collect();
+    let keep = |version: RowVersion| {
+        if aborted.contains(&version.xmin) {
+            return None;
+        }
+        if aborted.contains(&version.xmax) {
+            return Some(RowVersion { xmax: 0, ..version });
+        }
+        // Below the horizon a deleting transaction that did not roll back
+        // has committed
+        if version.xmax != 0 && version.xmax < horizon {
+            return None;
+        }
+        Some(version)
+    };
+
+    for table in tables.iter() {
+        let mut requests = MyVec::new();
+        requests.push((table.clone(), LockMode::Exclusive));
+        let _locks = lock_tables(schema, &requests)?;
+        rewrite_table_blocks(table, schema, |version, _| Ok(keep(version)))?;
+        // The indexes hold the same versions as the table
+        for index in table_indexes(schema, table)?.iter_mut() {
+            index.vacuum(keep);
+            wal::apply(&schema.name, index.write().into_iter().collect())?;
+            index.mark_stored();
+        }
+    }
+
+    // No version of the transactions that rolled back is left
+    if table.is_none() {
+        let mut writes = MyVec::new();
+        writes.push(FileWrite::replace(frozen_path(&schema.name), format!("{}\n", ended).into_bytes()));
+        wal::apply(&schema.name, writes)?;
+        with_log(&schema.name, |log| {
+            log.frozen = log.frozen.max(ended);
+            log.aborted.retain(|xid| *xid >= ended);
+            Ok(())
+        })?;
+    }
+    Ok(())
+}
@@ -1,24 +1,24 @@
 use crate::Schema;
+use crate::structs::{ Column, Row, RowId, RowVersion };
+use crate::value::{ ColumnType, Value };
 use crate::{ MyVec, MyHashMap };
+use crate::wal::{ self, FileWrite };
+use crate::transaction::Snapshot;
 use std::fs;
 use fs::OpenOptions;
-use std::io::{ BufReader, BufRead, Write };
-use std::sync::{ Arc, Mutex };
+use std::io::{ BufReader, BufRead };
 
 pub fn read_schema(path: &str) -> serde_json::Result<Schema> {
     let file = fs::File::open(path).expect("cannot open schema.json file");
     let reader = BufReader::new(file);
 
-    let schema: Schema = serde_json::from_reader(reader).expect("cannot parse json into structure");
+    let schema: Schema = serde_json::from_reader(reader)?;
 
     Ok(schema)
 }
 
 // Function to perform Cartesian product of rows from two tables
-pub fn cartesian_product(
-    table1: &MyVec<MyHashMap<String, String>>,
-    table2: &MyVec<MyHashMap<String, String>>
-) -> MyVec<MyHashMap<String, String>> {
+pub fn cartesian_product(table1: &MyVec<Row>, table2: &MyVec<Row>) -> MyVec<Row> {
     let mut result = MyVec::new();
 
     for row1 in table1.iter() {
@@ -28,13 +28,312 @@ pub fn cartesian_product(
             result.push(combined_row);
         }
     }
-    return result;
+    result
+}
+
+// Equi-join of two tables: a hash table is built over the rows of `right` and
+// probed with every row of `left`, rows with NULL in a key column never match
+pub fn hash_join(
+    left: &MyVec<Row>,
+    right: &MyVec<Row>,
+    left_columns: &MyVec<String>,
+    right_columns: &MyVec<String>
+) -> MyVec<Row> {
+    let buckets = build_hash_table(right, right_columns);
+
+    let mut result = MyVec::new();
+    for row in left.iter() {
+        if let Some(matches) = join_key(row, left_columns).and_then(|key| buckets.get(&key)) {
+            for i in matches.iter() {
+                let mut combined_row = row.clone();
+                combined_row.extend(right[*i].clone());
+                result.push(combined_row);
+            }
+        }
+    }
+    result
+}
+
+// Positions of the rows grouped by the values of the key columns
+pub fn build_hash_table(rows: &MyVec<Row>, columns: &MyVec<String>) -> MyHashMap<String, MyVec<usize>> {
+    let mut buckets: MyHashMap<String, MyVec<usize>> = MyHashMap::new();
+    for (i, row) in rows.iter().enumerate() {
+        if let Some(key) = join_key(row, columns) {
+            match buckets.get_mut(&key) {
+                Some(bucket) => bucket.push(i),
+                None => {
+                    let mut bucket = MyVec::new();
+                    bucket.push(i);
+                    buckets.insert(key, bucket);
+                }
+            }
+        }
+    }
+    buckets
+}
+
+pub fn join_key(row: &Row, columns: &MyVec<String>) -> Option<String> {
+    let mut key = String::new();
+    for column in columns.iter() {
+        let value = row.get(column)?;
+        if value.is_null() {
+            return None;
+        }
+        let part = value.hash_key();
+        key.push_str(&format!("{}:{}", part.len(), part));
+    }
+    Some(key)
+}
+
+// Key under which rows with equal values are put together, unlike join_key
+// NULLs are equal to each other here
+pub fn group_key(values: &MyVec<Value>) -> String {
+    let mut key = String::new();
+    for value in values.iter() {
+        let part = value.hash_key();
+        key.push_str(&format!("{}:{}", part.len(), part));
+    }
+    key
+}
+
+// SQL LIKE: `%` matches any sequence of characters, `_` matches exactly one
+pub fn like_match(text: &str, pattern: &str) -> bool {
+    let text: MyVec<char> = text.chars().collect();
+    let pattern: MyVec<char> = pattern.chars().collect();
+    let (mut t, mut p) = (0, 0);
+    // Position of the last `%` in the pattern and the text position it was matched at
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '_' || pattern[p] == text[t]) {
+            t += 1;
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == '%' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            // Let the last `%` swallow one more character
+            backtrack = Some((star, matched + 1));
+            p = star + 1;
+            t = matched + 1;
+        } else {
+            return false;
+        }
+    }
+    while p < pattern.len() && pattern[p] == '%' {
+        p += 1;
+    }
+    p == pattern.len()
+}
+
+pub fn column_names(columns: &[Column]) -> String {
+    columns
+        .iter()
+        .map(|column| column.name.as_str())
+        .collect::<MyVec<&str>>()
+        .join(",")
+}
+
+// First line of a block file, the row version comes before the columns
+pub fn block_header(columns: &[Column]) -> String {
+    format!("xmin,xmax,{}", column_names(columns))
+}
+
+// Turn a line of a block file into a row version and a row keyed by
+// `binding.column`, where binding is the table name or its alias in the query
+pub fn decode_row(
+    binding: &str,
+    columns: &[Column],
+    line: &str
+) -> Result<(RowVersion, Row), String> {
+    let fields = split_record(line).map_err(|e| format!("Corrupted data in {}: {}", binding, e))?;
+    // Lines written before row versions were stored have the columns only,
+    // they belong to no transaction
+    let (version, skip) = if fields.len() == columns.len() + 2 {
+        let xid = |i: usize| {
+            fields[i].0
+                .parse::<u64>()
+                .map_err(|_| format!("Corrupted row version in {}: '{}'", binding, fields[i].0))
+        };
+        (RowVersion { xmin: xid(0)?, xmax: xid(1)? }, 2)
+    } else {
+        (RowVersion { xmin: 0, xmax: 0 }, 0)
+    };
+    let mut row = MyHashMap::new();
+    for (i, column) in columns.iter().enumerate() {
+        let (raw, quoted) = if skip + i < fields.len() {
+            (fields[skip + i].0.as_str(), fields[skip + i].1)
+        } else {
+            (NULL_FIELD, false)
+        };
+        // Blocks written before NULLs were encoded have empty fields for them,
+        // an empty field is still NULL for every type but TEXT. A quoted
+        // field is always a value.
+        let is_null =
+            !quoted &&
+            (raw == NULL_FIELD || (raw.is_empty() && column.column_type != ColumnType::Text));
+        let value = if is_null {
+            Value::Null
+        } else {
+            Value::parse(raw, column.column_type).map_err(|e| {
+                format!("Corrupted data in {}.{}: {}", binding, column.name, e)
+            })?
+        };
+        row.insert(format!("{}.{}", binding, column.name), value);
+    }
+    Ok((version, row))
+}
+
+// How NULL is stored in block files, distinct from an empty string
+pub const NULL_FIELD: &str = "\\N";
+
+// A row as an RFC 4180 record. Fields with commas, quotes or line breaks
+// are quoted, quotes inside are doubled. Text that reads as the NULL marker
+// is quoted too.
+pub fn encode_row(values: &MyVec<Value>) -> String {
+    let mut line = String::new();
+    for (i, value) in values.iter().enumerate() {
+        if i > 0 {
+            line.push(',');
+        }
+        if value.is_null() {
+            line.push_str(NULL_FIELD);
+            continue;
+        }
+        let text = value.to_string();
+        push_field(&mut line, &text, text == NULL_FIELD || text.contains([',', '"', '\r', '\n']));
+    }
+    line
+}
+
+// A row of a query result as the server sends it. Fields are separated by
+// a space and NULL is sent as in the blocks. Fields that are empty or hold
+// spaces, tabs, quotes or line breaks are quoted as in the blocks.
+pub fn encode_result_row(values: &[Value]) -> String {
+    let mut line = String::new();
+    for (i, value) in values.iter().enumerate() {
+        if i > 0 {
+            line.push(' ');
+        }
+        if value.is_null() {
+            line.push_str(NULL_FIELD);
+            continue;
+        }
+        let text = value.to_string();
+        let quoted = text.is_empty() || text == NULL_FIELD || text.contains([' ', '\t', '"', '\r', '\n']);
+        push_field(&mut line, &text, quoted);
+    }
+    line
+}
+
+fn push_field(line: &mut String, text: &str, quoted: bool) {
+    if quoted {
+        line.push('"');
+        line.push_str(&text.replace('"', "\"\""));
+        line.push('"');
+    } else {
+        line.push_str(text);
+    }
+}
+
+// A row version as a line of a block file
+pub fn encode_version(version: RowVersion, values: &MyVec<Value>) -> String {
+    format!("{},{},{}", version.xmin, version.xmax, encode_row(values))
+}
+
+// Values of a row of one table in column order
+pub fn row_values(table: &str, columns: &[Column], row: &Row) -> MyVec<Value> {
+    columns
+        .iter()
+        .map(|column| row.get(&format!("{}.{}", table, column.name)).cloned().unwrap_or(Value::Null))
+        .collect()
+}
+
+// Split a record into its fields, each with whether it was quoted
+pub fn split_record(record: &str) -> Result<MyVec<(String, bool)>, String> {
+    let mut fields = MyVec::new();
+    let mut chars = record.chars().peekable();
+    loop {
+        let mut field = String::new();
+        let quoted = chars.peek() == Some(&'"');
+        if quoted {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some('"') if chars.peek() == Some(&'"') => {
+                        chars.next();
+                        field.push('"');
+                    }
+                    Some('"') => {
+                        break;
+                    }
+                    Some(c) => field.push(c),
+                    None => {
+                        return Err("unterminated quoted field".to_string());
+                    }
+                }
+            }
+            if !matches!(chars.peek(), None | Some(',')) {
+                return Err("unexpected character after a quoted field".to_string());
+            }
+        } else {
+            while let Some(c) = chars.peek() {
+                if *c == ',' {
+                    break;
+                }
+                field.push(*c);
+                chars.next();
+            }
+        }
+        fields.push((field, quoted));
+        if chars.next().is_none() {
+            return Ok(fields);
+        }
+    }
+}
+
+// Read one record, which spans several lines when a quoted field contains
+// line breaks. The line break ending the record is not included. Writers
+// end every record with a line break, a record without one is still being
+// appended by a transaction that has not committed and is skipped.
+pub fn read_record<R: BufRead>(reader: &mut R) -> Result<Option<String>, String> {
+    let mut record = String::new();
+    let mut in_quotes = false;
+    loop {
+        let mut line = String::new();
+        let read = reader.read_line(&mut line).map_err(|e| format!("Failed to read block: {}", e))?;
+        if read == 0 {
+            return Ok(None);
+        }
+        // A doubled quote flips the state twice, so counting is enough
+        for c in line.chars() {
+            if c == '"' {
+                in_quotes = !in_quotes;
+            }
+        }
+        record.push_str(&line);
+        if !in_quotes && record.ends_with('\n') {
+            record.pop();
+            if record.ends_with('\r') {
+                record.pop();
+            }
+            return Ok(Some(record));
+        }
+    }
 }
 
 pub fn read_all_table_data(
     table_name: &str,
-    schema: &Schema
-) -> Result<MyVec<MyHashMap<String, String>>, String> {
+    binding: &str,
+    schema: &Schema,
+    snapshot: &Snapshot
+) -> Result<MyVec<Row>, String> {
+    let columns = match schema.structure.get(table_name) {
+        Some(columns) => columns,
+        None => {
+            return Err(format!("No such table in DB: {}", table_name));
+        }
+    };
     let mut all_data = MyVec::new();
     let mut file_index = 1;
 
@@ -47,33 +346,18 @@ pub fn read_all_table_data(
 
         match file_result {
             Ok(file) => {
-                let file_mutex = Arc::new(Mutex::new(file));
+                // The table is locked by the caller, see crate::lock
+                let mut reader = BufReader::new(file);
 
-                // Блокируем файл для чтения
-                let file_lock = match file_mutex.lock() {
-                    Ok(locked_file) => locked_file,
-                    Err(_) => {
-                        return Err(format!("Failed to lock file: {}", file_path));
-                    }
-                };
-
-                // Если файл успешно заблокирован, выполняем чтение
-                let reader = BufReader::new(&*file_lock);
-                let mut lines = reader.lines();
-
-                // Read the header
-                let header_line = lines.next().unwrap().unwrap();
-                let headers: MyVec<&str> = header_line.split(',').collect();
-
-                // Read data from the file and add to all_data
-                for line in lines {
-                    let line = line.unwrap();
-                    let values: MyVec<&str> = line.split(',').collect();
-                    let mut row = MyHashMap::new();
-                    for (header, value) in headers.iter().zip(values.iter()) {
-                        row.insert(format!("{}.{}", table_name, header), value.to_string());
+                // Skip the header
+                read_record(&mut reader)?;
+
+                // Read the row versions the statement sees
+                while let Some(record) = read_record(&mut reader)? {
+                    let (version, row) = decode_row(binding, columns, &record)?;
+                    if snapshot.sees_version(&version) {
+                        all_data.push(row);
                     }
-                    all_data.push(row);
                 }
 
                 file_index += 1; // Move to the next file
@@ -88,40 +372,128 @@ pub fn read_all_table_data(
     Ok(all_data)
 }
 
-pub fn find_not_full_csv(schema: &Schema, table: &str) -> Result<i32, String> {
-    let mut not_full_csv_index = 0;
-    let file_mutex = Arc::new(Mutex::new(()));
+// The first block of a table from block `from` on that has room for more
+// rows, with the number of rows it holds. The block may not exist yet.
+pub fn find_not_full_csv(schema: &Schema, table: &str, from: i32) -> Result<(i32, usize), String> {
+    let mut not_full_csv_index = from - 1;
 
     loop {
         not_full_csv_index += 1;
         let path = format!("{}/{}/{}.csv", schema.name, table, not_full_csv_index);
 
-        // Блокируем доступ к файлу с помощью Mutex
-        let _lock = match file_mutex.lock() {
-            Ok(m) => m,
-            Err(_) => {
-                return Err("Table is blocked".to_string());
-            }
-        }; // Блокировка синхронизирована
-
         // Try to open the file
         match OpenOptions::new().read(true).open(&path) {
             Ok(file) => {
-                // If the file is open, check the number of lines
-                let reader = BufReader::new(file);
-                if reader.lines().count() - 1 < (schema.tuples_limit as usize) {
+                // If the file is open, check the number of records
+                let mut reader = BufReader::new(file);
+                let mut records: usize = 0;
+                while read_record(&mut reader)?.is_some() {
+                    records += 1;
+                }
+                let rows = records.saturating_sub(1);
+                if rows < (schema.tuples_limit as usize) {
                     // If the file is not full, return it
-                    return Ok(not_full_csv_index);
+                    return Ok((not_full_csv_index, rows));
                 }
                 // If the file is full, continue searching
             }
             Err(_) => {
-                // If the file does not exist, create a new one
-                let mut file = fs::File::create(&path).expect("failed to create new csv file");
-                let columns = schema.structure.get(table).unwrap().join(",");
-                writeln!(file, "{}", columns).unwrap();
-                return Ok(not_full_csv_index);
+                // The caller creates the file along with its rows
+                return Ok((not_full_csv_index, 0));
+            }
+        }
+    }
+}
+
+// Rewrite the blocks of a table, giving each row version the version
+// returned by `rewrite` or dropping it when that is None. Only blocks with
+// changes are written.
+pub fn rewrite_table_blocks<F>(table: &str, schema: &Schema, mut rewrite: F) -> Result<(), String>
+    where F: FnMut(RowVersion, &Row) -> Result<Option<RowVersion>, String>
+{
+    // All blocks are replaced together through the write-ahead log
+    let mut writes = MyVec::new();
+    table_block_writes(table, schema, &mut writes, |_, version, row| rewrite(version, row))?;
+    wal::apply(&schema.name, writes)
+}
+
+// Call `visit` with every row version of a table and where it is stored,
+// deleted versions included
+pub fn scan_table_versions<F>(table: &str, schema: &Schema, mut visit: F) -> Result<(), String>
+    where F: FnMut(RowId, RowVersion, &Row) -> Result<(), String>
+{
+    table_block_writes(table, schema, &mut MyVec::new(), |id, version, row| {
+        visit(id, version, row)?;
+        Ok(Some(version))
+    })
+}
+
+// Add the writes that rewrite the blocks of a table as rewrite_table_blocks
+// does to `writes`, for a statement that applies them along with writes of
+// its own
+pub fn table_block_writes<F>(
+    table: &str,
+    schema: &Schema,
+    writes: &mut MyVec<FileWrite>,
+    mut rewrite: F
+) -> Result<(), String>
+    where F: FnMut(RowId, RowVersion, &Row) -> Result<Option<RowVersion>, String>
+{
+    let head = match schema.structure.get(table) {
+        Some(head) => head,
+        None => {
+            return Err("No such table in DB".to_string());
+        }
+    };
+    let mut file_index = 0;
+
+    loop {
+        file_index += 1;
+        let mut remaining_lines: MyVec<String> = MyVec::new();
+        remaining_lines.push(block_header(head)); // Добавляем заголовок в оставшиеся строки
+        let mut changed = false;
+
+        let path = format!("{}/{}/{}.csv", schema.name, table, file_index);
+        let file_result = OpenOptions::new().read(true).open(&path);
+
+        // Если файл не найден, выходим из цикла
+        let file = match file_result {
+            Ok(file) => file,
+            Err(_) => {
+                break;
             }
+        };
+
+        let mut reader = BufReader::new(file);
+        read_record(&mut reader)?; // Пропускаем заголовок
+
+        // Обрабатываем каждую запись
+        let mut line_index = 0;
+        while let Some(line) = read_record(&mut reader)? {
+            let (version, row) = decode_row(table, head, &line)?;
+            let id = RowId { block: file_index, line: line_index };
+            line_index += 1;
+            match rewrite(id, version, &row)? {
+                Some(new_version) if new_version == version => remaining_lines.push(line),
+                Some(new_version) => {
+                    remaining_lines.push(encode_version(new_version, &row_values(table, head, &row)));
+                    changed = true;
+                }
+                None => {
+                    changed = true;
+                }
+            }
+        }
+        if !changed {
+            continue;
+        }
+
+        let mut content = String::new();
+        for line in remaining_lines.iter() {
+            content.push_str(line);
+            content.push('\n');
         }
+        writes.push(FileWrite::replace(path, content.into_bytes()));
     }
+    Ok(())
 }
@@ -0,0 +1,260 @@
+use std::cmp::Ordering;
+use std::fmt;
+use serde::{ Deserialize, Serialize };
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum ColumnType {
+    Integer,
+    Bigint,
+    Real,
+    Text,
+    Boolean,
+    Timestamp,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Text(String),
+    Boolean(bool),
+    // Seconds since 1970-01-01 00:00:00
+    Timestamp(i64),
+}
+
+impl ColumnType {
+    // Values of both types can be matched by their hash keys
+    pub fn same_kind(self, other: ColumnType) -> bool {
+        let numeric = |t| matches!(t, ColumnType::Integer | ColumnType::Bigint | ColumnType::Real);
+        self == other || (numeric(self) && numeric(other))
+    }
+}
+
+impl fmt::Display for ColumnType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ColumnType::Integer => "INTEGER",
+            ColumnType::Bigint => "BIGINT",
+            ColumnType::Real => "REAL",
+            ColumnType::Text => "TEXT",
+            ColumnType::Boolean => "BOOLEAN",
+            ColumnType::Timestamp => "TIMESTAMP",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Null => write!(f, "NULL"),
+            Value::Integer(i) => write!(f, "{}", i),
+            Value::Real(r) => write!(f, "{}", r),
+            Value::Text(s) => write!(f, "{}", s),
+            Value::Boolean(b) => write!(f, "{}", b),
+            Value::Timestamp(ts) => write!(f, "{}", format_timestamp(*ts)),
+        }
+    }
+}
+
+impl Value {
+    // Parse raw text into a value of the given column type
+    pub fn parse(raw: &str, column_type: ColumnType) -> Result<Value, String> {
+        let parsed = match column_type {
+            ColumnType::Integer =>
+                raw
+                    .trim()
+                    .parse::<i32>()
+                    .ok()
+                    .map(|i| Value::Integer(i as i64)),
+            ColumnType::Bigint => raw.trim().parse::<i64>().ok().map(Value::Integer),
+            ColumnType::Real =>
+                raw
+                    .trim()
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|r| r.is_finite())
+                    .map(Value::Real),
+            ColumnType::Text => Some(Value::Text(raw.to_string())),
+            ColumnType::Boolean => parse_bool(raw).map(Value::Boolean),
+            ColumnType::Timestamp => parse_timestamp(raw).map(Value::Timestamp),
+        };
+        parsed.ok_or_else(|| format!("'{}' is not a valid {} value", raw, column_type))
+    }
+
+    pub fn is_null(&self) -> bool {
+        matches!(self, Value::Null)
+    }
+
+    // Convert a literal from a query into the type of a column
+    pub fn coerce(&self, column_type: ColumnType) -> Result<Value, String> {
+        match self {
+            Value::Null => Ok(Value::Null),
+            Value::Text(s) => Value::parse(s, column_type),
+            other => Value::parse(&other.to_string(), column_type),
+        }
+    }
+
+    // Equal values of the same kind get equal keys, so the key can be used
+    // to match values through a hash table
+    pub fn hash_key(&self) -> String {
+        match self {
+            Value::Null => "n".to_string(),
+            Value::Integer(i) => format!("i{}", i),
+            Value::Real(r) if r.fract() == 0.0 && r.abs() < 9.0e18 => format!("i{}", *r as i64),
+            Value::Real(r) => format!("r{}", r),
+            Value::Text(s) => format!("t{}", s),
+            Value::Boolean(b) => format!("b{}", b),
+            Value::Timestamp(ts) => format!("d{}", ts),
+        }
+    }
+
+    pub fn compare(&self, other: &Value) -> Option<Ordering> {
+        match (self, other) {
+            (Value::Null, _) | (_, Value::Null) => None,
+            (_, Value::Text(literal)) => self.compare_literal(literal),
+            (Value::Text(literal), _) => other.compare_literal(literal).map(Ordering::reverse),
+            (Value::Integer(a), Value::Integer(b)) => Some(a.cmp(b)),
+            (Value::Integer(a), Value::Real(b)) => (*a as f64).partial_cmp(b),
+            (Value::Real(a), Value::Integer(b)) => a.partial_cmp(&(*b as f64)),
+            (Value::Real(a), Value::Real(b)) => a.partial_cmp(b),
+            (Value::Boolean(a), Value::Boolean(b)) => Some(a.cmp(b)),
+            (Value::Timestamp(a), Value::Timestamp(b)) => Some(a.cmp(b)),
+            _ => None,
+        }
+    }
+
+    // Total order used for sorting: NULL comes after every other value and
+    // text is compared as plain strings
+    pub fn sort_cmp(&self, other: &Value) -> Ordering {
+        match (self, other) {
+            (Value::Null, Value::Null) => Ordering::Equal,
+            (Value::Null, _) => Ordering::Greater,
+            (_, Value::Null) => Ordering::Less,
+            (Value::Text(a), Value::Text(b)) => a.cmp(b),
+            _ => self.compare(other).unwrap_or(Ordering::Equal),
+        }
+    }
+
+    // Compare the value with a literal taken from a query, interpreting the
+    // literal according to the type of the value
+    pub fn compare_literal(&self, literal: &str) -> Option<Ordering> {
+        match self {
+            Value::Null => None,
+            Value::Integer(i) => {
+                if let Ok(other) = literal.trim().parse::<i64>() {
+                    Some(i.cmp(&other))
+                } else {
+                    let other = literal.trim().parse::<f64>().ok()?;
+                    (*i as f64).partial_cmp(&other)
+                }
+            }
+            Value::Real(r) => r.partial_cmp(&literal.trim().parse::<f64>().ok()?),
+            Value::Text(s) => {
+                // Text that looks like numbers on both sides is compared numerically
+                match (s.trim().parse::<f64>(), literal.trim().parse::<f64>()) {
+                    (Ok(left), Ok(right)) => left.partial_cmp(&right),
+                    _ => Some(s.as_str().cmp(literal)),
+                }
+            }
+            Value::Boolean(b) => Some(b.cmp(&parse_bool(literal)?)),
+            Value::Timestamp(ts) => Some(ts.cmp(&parse_timestamp(literal)?)),
+        }
+    }
+}
+
+fn parse_bool(raw: &str) -> Option<bool> {
+    match raw.trim().to_ascii_lowercase().as_str() {
+        "true" | "t" | "1" => Some(true),
+        "false" | "f" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+// Accepts "YYYY-MM-DD", "YYYY-MM-DD HH:MM:SS" and "YYYY-MM-DDTHH:MM:SS"
+fn parse_timestamp(raw: &str) -> Option<i64> {
+    let raw = raw.trim();
+    let (date, time) = match raw.split_once([' ', 'T']) {
+        Some((date, time)) => (date, Some(time)),
+        None => (raw, None),
+    };
+
+    let date_parts: Vec<&str> = date.split('-').collect();
+    if date_parts.len() != 3 || date_parts[0].len() != 4 {
+        return None;
+    }
+    let year: i64 = date_parts[0].parse().ok()?;
+    let month: u32 = date_parts[1].parse().ok()?;
+    let day: u32 = date_parts[2].parse().ok()?;
+    if !(1..=12).contains(&month) || day == 0 || day > days_in_month(year, month) {
+        return None;
+    }
+
+    let mut seconds = 0;
+    if let Some(time) = time {
+        let time_parts: Vec<&str> = time.split(':').collect();
+        if time_parts.len() != 3 {
+            return None;
+        }
+        let hour: i64 = time_parts[0].parse().ok()?;
+        let minute: i64 = time_parts[1].parse().ok()?;
+        let second: i64 = time_parts[2].parse().ok()?;
+        if !(0..24).contains(&hour) || !(0..60).contains(&minute) || !(0..60).contains(&second) {
+            return None;
+        }
+        seconds = hour * 3600 + minute * 60 + second;
+    }
+
+    Some(days_from_civil(year, month, day) * 86400 + seconds)
+}
+
+fn format_timestamp(ts: i64) -> String {
+    let days = ts.div_euclid(86400);
+    let seconds = ts.rem_euclid(86400);
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        (seconds % 3600) / 60,
+        seconds % 60
+    )
+}
+
+fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        2 if (year % 4 == 0 && year % 100 != 0) || year % 400 == 0 => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// Days since 1970-01-01 for a proleptic Gregorian date
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + (day as i64) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    let year = year_of_era + era * 400 + (if month <= 2 { 1 } else { 0 });
+    (year, month, day)
+}
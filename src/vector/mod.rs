@@ -1,4 +1,4 @@
-use std::ptr::{ self, null_mut };
+use std::ptr;
 use std::ops::{ Index, IndexMut, RangeFrom, Range };
 use std::iter::FromIterator;
 
@@ -8,6 +8,10 @@ pub struct MyVec<T> {
     capacity: usize,
 }
 
+// SAFETY: MyVec owns its elements like Vec does, the pointer is never shared
+unsafe impl<T: Send> Send for MyVec<T> {}
+unsafe impl<T: Sync> Sync for MyVec<T> {}
+
 impl<T> MyVec<T> {
     pub fn new() -> Self {
         MyVec {
@@ -39,7 +43,7 @@ impl<T> MyVec<T> {
 
         // Copy old elements to new memory
         unsafe {
-            if self.data != null_mut() {
+            if !self.data.is_null() {
                 ptr::copy_nonoverlapping(self.data, new_data, self.size);
             }
 
@@ -61,12 +65,23 @@ impl<T> MyVec<T> {
         self.size
     }
 
-    pub fn iter(&self) -> MyVecIter<T> {
+    pub fn iter(&self) -> MyVecIter<'_, T> {
         MyVecIter {
             vec: self,
             index: 0,
         }
     }
+
+    pub fn as_mut_slice(&mut self) -> &mut [T] {
+        if self.data.is_null() {
+            return &mut [];
+        }
+        unsafe { std::slice::from_raw_parts_mut(self.data, self.size) }
+    }
+
+    pub fn iter_mut(&mut self) -> std::slice::IterMut<'_, T> {
+        self.as_mut_slice().iter_mut()
+    }
 }
 
 // Implementation of the `Index` trait for access through `[]`
@@ -104,13 +119,26 @@ impl<T: Clone> Clone for MyVec<T> {
         let mut new_vec = MyVec::new();
         for i in 0..self.size {
             unsafe {
-                let item = ptr::read(self.data.add(i)); // Копируем элемент
+                let item = &*self.data.add(i); // Берём ссылку на элемент
                 new_vec.push(item.clone()); // Клонируем элемент и добавляем его в новый вектор
             }
         }
         new_vec
     }
 }
+
+impl<T: PartialEq> PartialEq for MyVec<T> {
+    fn eq(&self, other: &MyVec<T>) -> bool {
+        self.size == other.size && self.iter().zip(other.iter()).all(|(a, b)| a == b)
+    }
+}
+
+impl<T: std::fmt::Debug> std::fmt::Debug for MyVec<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
 impl<T> Index<Range<usize>> for MyVec<T> {
     type Output = [T]; // Specify that the returned type is a slice
 
@@ -150,6 +178,14 @@ impl<T> FromIterator<T> for MyVec<T> {
     }
 }
 
+impl<T> Extend<T> for MyVec<T> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        for value in iter {
+            self.push(value);
+        }
+    }
+}
+
 impl MyVec<&str> {
     pub fn join(&self, sep: &str) -> String {
         if self.size == 0 {
@@ -191,10 +227,10 @@ impl<'a, T> Iterator for MyVecIter<'a, T> {
 impl<T> Drop for MyVec<T> {
     fn drop(&mut self) {
         // If data is not null_mut, free the memory
-        if self.data != null_mut() {
+        if !self.data.is_null() {
             unsafe {
                 // Deallocate memory for elements
-                for i in 1..self.size {
+                for i in 0..self.size {
                     // Call the destructor for each element
                     ptr::drop_in_place(self.data.add(i));
                 }
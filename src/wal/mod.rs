@@ -0,0 +1,321 @@
+use std::fs::{ self, OpenOptions };
+use std::io::{ self, Read, Seek, SeekFrom, Write };
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::{ Mutex, MutexGuard };
+use crate::MyVec;
+
+// Every change to the files of a database is first appended to the
+// write-ahead log at `{database}/.wal` and synced, then applied to the
+// files. Once the files are synced the log is emptied again, so at startup
+// the log holds at most the change that was interrupted, and replaying it
+// finishes that change.
+//
+// A log record is
+//   [payload length: u32][checksum of the payload: u64][payload]
+// and the payload is a list of writes
+//   [path length: u32][path][offset: u64][data length: u32][data]
+// All numbers are little endian. A record that was cut short by a crash
+// fails the length or checksum test and is ignored.
+//
+// A change is logged, applied and cleared while the log is held: by the
+// mutex below within the server and by an advisory lock on the log file
+// against other server processes on the same database. A change that is
+// still in the log when it is taken was left by a writer that failed, and
+// is finished before the next one.
+
+// Writers of all databases take turns in this process
+static WAL_LOCK: Mutex<()> = Mutex::new(());
+
+// The write-ahead log of a database, held until dropped. Closing the file
+// releases the advisory lock.
+struct HeldLog {
+    file: fs::File,
+    _guard: MutexGuard<'static, ()>,
+}
+
+pub fn wal_path(database: &str) -> String {
+    format!("{}/.wal", database)
+}
+
+// Put `data` at `offset` of a file and cut the file right after it.
+// Applying the same write twice gives the same file.
+pub struct FileWrite {
+    path: String,
+    // None appends to the file, the offset is fixed when the write is logged
+    offset: Option<u64>,
+    data: Vec<u8>,
+}
+
+// How to put a file back as it was before a write
+enum Undo {
+    Restore(FileWrite),
+    Remove(String),
+}
+
+impl FileWrite {
+    // Replace the whole content of a file, creating it if needed
+    pub fn replace(path: String, data: Vec<u8>) -> FileWrite {
+        FileWrite { path, offset: Some(0), data }
+    }
+
+    pub fn append(path: String, data: Vec<u8>) -> FileWrite {
+        FileWrite { path, offset: None, data }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    // Put more data right after the data of the write
+    pub fn extend(&mut self, data: &[u8]) {
+        self.data.extend_from_slice(data);
+    }
+}
+
+impl Undo {
+    fn apply(&self) -> io::Result<()> {
+        match self {
+            Undo::Restore(write) => apply_write(write),
+            Undo::Remove(path) => {
+                match fs::remove_file(path) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                    _ => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+// Log the writes and apply them, all of them survive a crash or none does
+pub fn apply(database: &str, writes: MyVec<FileWrite>) -> Result<(), String> {
+    if writes.len() == 0 {
+        return Ok(());
+    }
+    apply_prepared(database, || Ok((writes, ())))
+}
+
+// Apply the writes `prepare` makes while the log is held, for a change
+// that depends on what the other writers have stored
+pub fn apply_prepared<T, F>(database: &str, prepare: F) -> Result<T, String>
+    where F: FnOnce() -> Result<(MyVec<FileWrite>, T), String>
+{
+    let mut log = hold_log(database)?;
+    replay(&mut log)?;
+    let (mut writes, prepared) = prepare()?;
+    if writes.len() == 0 {
+        return Ok(prepared);
+    }
+    log_writes(&mut log, &mut writes)?;
+
+    let mut undos = MyVec::new();
+    for write in writes.iter() {
+        let applied = undo_of(write).and_then(|undo| {
+            undos.push(undo);
+            apply_write(write)
+        });
+        if let Err(e) = applied {
+            let error = format!("Failed to write {}: {}", write.path, e);
+            // The files are put back in the reverse order, if that fails
+            // too the change stays logged and is finished before the next
+            // one or at startup
+            for position in (0..undos.len()).rev() {
+                if let Err(e) = undos[position].apply() {
+                    return Err(format!("{}, the change is finished later: {}", error, e));
+                }
+            }
+            clear_log(&log)?;
+            return Err(error);
+        }
+    }
+    clear_log(&log)?;
+    Ok(prepared)
+}
+
+// Only append the writes to the log, as if the server stopped before
+// applying them
+#[cfg(test)]
+pub fn log(database: &str, mut writes: MyVec<FileWrite>) -> Result<(), String> {
+    let mut log = hold_log(database)?;
+    log_writes(&mut log, &mut writes)
+}
+
+// Take the log of a database from the other writers, waiting for them
+fn hold_log(database: &str) -> Result<HeldLog, String> {
+    let guard = match WAL_LOCK.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let file = OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(wal_path(database))
+        .map_err(|e| format!("Failed to open the write-ahead log: {}", e))?;
+    // Another process holds the log only while it applies one change
+    loop {
+        // SAFETY: flock only takes the descriptor of the file opened above
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } == 0 {
+            return Ok(HeldLog { file, _guard: guard });
+        }
+        let error = io::Error::last_os_error();
+        if error.kind() != io::ErrorKind::Interrupted {
+            return Err(format!("Failed to lock the write-ahead log: {}", error));
+        }
+    }
+}
+
+fn log_writes(log: &mut HeldLog, writes: &mut MyVec<FileWrite>) -> Result<(), String> {
+    for write in writes.iter_mut() {
+        if write.offset.is_none() {
+            let length = fs::metadata(&write.path).map(|metadata| metadata.len()).unwrap_or(0);
+            write.offset = Some(length);
+        }
+    }
+    log.file.write_all(&encode_record(writes))
+        .and_then(|_| log.file.sync_data())
+        .map_err(|e| format!("Failed to write the write-ahead log: {}", e))
+}
+
+fn clear_log(log: &HeldLog) -> Result<(), String> {
+    log.file.set_len(0)
+        .and_then(|_| log.file.sync_data())
+        .map_err(|e| format!("Failed to truncate the write-ahead log: {}", e))
+}
+
+// Finish the change that was being applied when the server stopped,
+// returns how many logged records were replayed
+pub fn recover(database: &str) -> Result<usize, String> {
+    let mut log = hold_log(database)?;
+    replay(&mut log)
+}
+
+// Apply the records left in the log and clear it
+fn replay(log: &mut HeldLog) -> Result<usize, String> {
+    let mut content = Vec::new();
+    log.file.seek(SeekFrom::Start(0))
+        .and_then(|_| log.file.read_to_end(&mut content))
+        .map_err(|e| format!("Failed to read the write-ahead log: {}", e))?;
+    if content.is_empty() {
+        return Ok(0);
+    }
+
+    let mut replayed = 0;
+    let mut position = 0;
+    while let Some((writes, next)) = decode_record(&content, position) {
+        for write in writes.iter() {
+            apply_write(write).map_err(|e| format!("Failed to replay a write to {}: {}", write.path, e))?;
+        }
+        replayed += 1;
+        position = next;
+    }
+    clear_log(log)?;
+    Ok(replayed)
+}
+
+// Read what a write is going to overwrite
+fn undo_of(write: &FileWrite) -> io::Result<Undo> {
+    let offset = write.offset.unwrap_or(0);
+    let mut file = match fs::File::open(&write.path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Ok(Undo::Remove(write.path.clone()));
+        }
+        Err(e) => {
+            return Err(e);
+        }
+    };
+    let mut data = Vec::new();
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_to_end(&mut data)?;
+    Ok(Undo::Restore(FileWrite { path: write.path.clone(), offset: Some(offset), data }))
+}
+
+fn apply_write(write: &FileWrite) -> io::Result<()> {
+    let offset = write.offset.unwrap_or(0);
+    // Readers take no locks, so a file that is replaced is written aside
+    // and renamed over the old one. A reader sees either file in full.
+    if offset == 0 {
+        let temporary = format!("{}.tmp", write.path);
+        let mut file = fs::File::create(&temporary)?;
+        file.write_all(&write.data)?;
+        file.sync_all()?;
+        fs::rename(&temporary, &write.path)?;
+        if let Some(parent) = Path::new(&write.path).parent() {
+            fs::File::open(parent)?.sync_all()?;
+        }
+        return Ok(());
+    }
+    let created = !Path::new(&write.path).exists();
+    let mut file = OpenOptions::new().create(true).truncate(false).write(true).open(&write.path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(&write.data)?;
+    file.set_len(offset + (write.data.len() as u64))?;
+    file.sync_all()?;
+    // A new file is only durable once its directory entry is
+    if created {
+        if let Some(parent) = Path::new(&write.path).parent() {
+            fs::File::open(parent)?.sync_all()?;
+        }
+    }
+    Ok(())
+}
+
+fn encode_record(writes: &MyVec<FileWrite>) -> Vec<u8> {
+    let mut payload = Vec::new();
+    for write in writes.iter() {
+        payload.extend_from_slice(&(write.path.len() as u32).to_le_bytes());
+        payload.extend_from_slice(write.path.as_bytes());
+        payload.extend_from_slice(&write.offset.unwrap_or(0).to_le_bytes());
+        payload.extend_from_slice(&(write.data.len() as u32).to_le_bytes());
+        payload.extend_from_slice(&write.data);
+    }
+    let mut record = Vec::with_capacity(payload.len() + 12);
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    record.extend_from_slice(&checksum(&payload).to_le_bytes());
+    record.extend_from_slice(&payload);
+    record
+}
+
+// The writes of the record at `position` and the position of the next
+// record, None for the end of the log or a damaged record
+fn decode_record(content: &[u8], position: usize) -> Option<(MyVec<FileWrite>, usize)> {
+    let length = read_u32(content, position)? as usize;
+    let expected = read_u64(content, position + 4)?;
+    let payload = content.get(position + 12..(position + 12).checked_add(length)?)?;
+    if checksum(payload) != expected {
+        return None;
+    }
+
+    let mut writes = MyVec::new();
+    let mut at = 0;
+    while at < payload.len() {
+        let path_length = read_u32(payload, at)? as usize;
+        let path = String::from_utf8(payload.get(at + 4..at + 4 + path_length)?.to_vec()).ok()?;
+        at += 4 + path_length;
+        let offset = read_u64(payload, at)?;
+        let data_length = read_u32(payload, at + 8)? as usize;
+        let data = payload.get(at + 12..at + 12 + data_length)?.to_vec();
+        at += 12 + data_length;
+        writes.push(FileWrite { path, offset: Some(offset), data });
+    }
+    Some((writes, position + 12 + length))
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn read_u64(bytes: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(bytes.get(at..at + 8)?.try_into().ok()?))
+}
+
+// 64-bit FNV-1a
+fn checksum(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}